@@ -0,0 +1,924 @@
+use crate::executor::parse_depfile;
+use crate::remote::Remote;
+use crate::ui;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Bumped whenever the key derivation or entry layout changes.
+const KEY_VERSION: &str = "ghost-cas-v1";
+/// Per-build hit/miss log, one byte per cached action, in the build dir.
+pub const BUILD_LOG: &str = ".ghost_cache_log";
+/// Appended to `BUILD_LOG` for the marker written when the remote cache fails.
+pub const REMOTE_DOWN_SUFFIX: &str = ".remote-down";
+const STATS_FILE: &str = "stats";
+/// Direct-mode manifests, by command hash; see `manifest_lookup`.
+const MANIFESTS: &str = "manifests";
+const MANIFEST_RECORDS: usize = 8;
+const DEFAULT_MAX_SIZE: u64 = 5 << 30;
+
+/// `GHOST_CACHE_DIR`, else `$XDG_CACHE_HOME/ghost/cas`, else `~/.cache/ghost/cas`.
+pub fn cache_dir() -> PathBuf {
+    if let Some(d) = env::var_os("GHOST_CACHE_DIR").filter(|d| !d.is_empty()) {
+        return PathBuf::from(d);
+    }
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .or_else(|| env::var_os("USERPROFILE"))
+                .map(|h| PathBuf::from(h).join(".cache"))
+        })
+        .unwrap_or_else(|| PathBuf::from(".cache"));
+    base.join("ghost").join("cas")
+}
+
+/// Caching is on unless `--no-cache` or `GHOST_CACHE=0|off|false`.
+pub fn enabled(no_cache: bool) -> bool {
+    !no_cache
+        && !matches!(
+            env::var("GHOST_CACHE").as_deref(),
+            Ok("0") | Ok("off") | Ok("false")
+        )
+}
+
+/// Remote cache settings: `[cache]` in the root `ghost.build`, overridden by
+/// `GHOST_REMOTE_CACHE` (empty disables it) and `GHOST_REMOTE_CACHE_MODE`.
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    pub url: String,
+    pub write: bool,
+}
+
+pub fn remote_config(cfg: Option<&crate::manifest::CacheConfig>) -> Result<Option<RemoteConfig>> {
+    let url = match env::var("GHOST_REMOTE_CACHE") {
+        Ok(u) => u,
+        Err(_) => cfg.and_then(|c| c.remote.clone()).unwrap_or_default(),
+    };
+    if url.is_empty() {
+        return Ok(None);
+    }
+    Remote::parse(&url)?;
+    let mode = match env::var("GHOST_REMOTE_CACHE_MODE") {
+        Ok(m) => m,
+        Err(_) => cfg
+            .and_then(|c| c.mode.clone())
+            .unwrap_or_else(|| "read-only".into()),
+    };
+    let write = match mode.as_str() {
+        "read-only" | "ro" => false,
+        "read-write" | "rw" => true,
+        other => {
+            bail!("remote cache mode: expected \"read-only\" or \"read-write\", got \"{other}\"")
+        }
+    };
+    Ok(Some(RemoteConfig { url, write }))
+}
+
+/// The `$launcher` prefix for compile and link rules: `ghost cache-exec ... --`.
+pub fn launcher(build_dir: &str, remote: Option<&RemoteConfig>) -> Result<String> {
+    let exe = env::current_exe().context("locate the ghost executable")?;
+    let mut l = format!(
+        "\"{}\" cache-exec --dir \"{}\" --log \"{}/{}\"",
+        exe.display(),
+        cache_dir().display(),
+        build_dir,
+        BUILD_LOG
+    );
+    if let Some(r) = remote {
+        l.push_str(&format!(" --remote \"{}\"", r.url));
+        if r.write {
+            l.push_str(" --remote-write");
+        }
+    }
+    l.push_str(" --");
+    Ok(l)
+}
+
+fn entry_dir(root: &Path, key: &str) -> PathBuf {
+    root.join(&key[..2]).join(key)
+}
+
+fn mtime_secs(m: &fs::Metadata) -> u64 {
+    m.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// The compiler is identified by its resolved path, size and mtime, so upgrading it
+// invalidates every entry without running `--version` for each compile.
+fn compiler_id(compiler: &str) -> String {
+    let path = if compiler.contains('/') || compiler.contains('\\') {
+        Some(PathBuf::from(compiler))
+    } else {
+        env::var_os("PATH").and_then(|p| {
+            env::split_paths(&p)
+                .map(|d| d.join(compiler))
+                .find(|c| c.is_file())
+        })
+    };
+    let Some(path) = path.and_then(|p| p.canonicalize().ok()) else {
+        return compiler.to_string();
+    };
+    match fs::metadata(&path) {
+        Ok(m) => format!("{} {} {}", path.display(), m.len(), mtime_secs(&m)),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+struct Action<'a> {
+    out: &'a str,
+    depfile: Option<&'a str>,
+    /// Everything that affects the output, with output paths left out so the same
+    /// action is shared between build dirs.
+    normalized: Vec<&'a str>,
+    /// For compiles, the command that preprocesses the source. Links are keyed by
+    /// the contents of their input files and libraries instead.
+    preprocess: Option<Vec<&'a str>>,
+}
+
+fn split_action(argv: &[String]) -> Option<Action<'_>> {
+    let mut out = None;
+    let mut depfile = None;
+    let mut compile = false;
+    let mut normalized = vec![];
+    let mut preprocess = vec![];
+    let mut it = argv.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "-o" => out = Some(it.next()?.as_str()),
+            "-MF" => depfile = Some(it.next()?.as_str()),
+            "-MMD" | "-MD" => normalized.push(a.as_str()),
+            "-c" => {
+                compile = true;
+                normalized.push(a.as_str());
+            }
+            _ => {
+                normalized.push(a.as_str());
+                preprocess.push(a.as_str());
+            }
+        }
+    }
+    preprocess.push("-E");
+    Some(Action {
+        out: out?,
+        depfile,
+        normalized,
+        preprocess: compile.then_some(preprocess),
+    })
+}
+
+// Everything but the inputs' contents: the key version, the compiler and the
+// normalized command line.
+fn command_hash(compiler: &str, action: &Action) -> Sha256 {
+    let mut h = Sha256::new();
+    for part in [KEY_VERSION, &compiler_id(compiler)]
+        .into_iter()
+        .chain(action.normalized.iter().copied())
+    {
+        h.update(part.as_bytes());
+        h.update([0]);
+    }
+    h
+}
+
+// Direct mode, as in ccache: a compile is first looked up by its command alone. The
+// manifest stored under that key records, for earlier compiles, each file the
+// depfile listed (the source and every header) with its hash, and the entry the
+// compile produced. If all of them still hash the same, the entry is reused without
+// running the preprocessor.
+
+fn manifest_path(root: &Path, direct: &str) -> PathBuf {
+    root.join(MANIFESTS).join(&direct[..2]).join(direct)
+}
+
+// (hash, path) for each file, or `None` if one is missing or changed after `started`,
+// as it may then not be what was compiled.
+fn file_digests(files: &[&str], started: SystemTime) -> Option<Vec<(String, String)>> {
+    files
+        .iter()
+        .map(|f| {
+            let modified = fs::metadata(f).and_then(|m| m.modified()).ok()?;
+            if modified >= started {
+                return None;
+            }
+            let bytes = fs::read(f).ok()?;
+            Some((hex::encode(Sha256::digest(&bytes)), f.to_string()))
+        })
+        .collect()
+}
+
+// Key for an entry found in direct mode only, from the files it was compiled from.
+fn deps_key(direct: &str, files: &[(String, String)]) -> String {
+    let mut h = Sha256::new();
+    h.update(direct.as_bytes());
+    for (digest, path) in files {
+        h.update([0]);
+        h.update(path.as_bytes());
+        h.update([0]);
+        h.update(digest.as_bytes());
+    }
+    hex::encode(h.finalize())
+}
+
+// The entry key of the first recorded compile whose files are all unchanged. A
+// manifest is blank-line separated records: the key, then `<hash> <path>` lines.
+fn manifest_lookup(root: &Path, direct: &str) -> Option<String> {
+    let text = fs::read_to_string(manifest_path(root, direct)).ok()?;
+    text.split("\n\n").find_map(|rec| {
+        let mut lines = rec.lines();
+        let key = lines.next().filter(|k| !k.is_empty())?;
+        lines
+            .all(|l| {
+                l.split_once(' ').is_some_and(|(digest, path)| {
+                    fs::read(path).is_ok_and(|b| hex::encode(Sha256::digest(&b)) == digest)
+                })
+            })
+            .then(|| key.to_string())
+    })
+}
+
+// Puts the compile first in its manifest, keeping the `MANIFEST_RECORDS` most recent.
+fn manifest_add(root: &Path, direct: &str, key: &str, files: &[(String, String)]) {
+    let mut rec = key.to_string();
+    for (digest, path) in files {
+        rec.push_str(&format!("\n{digest} {path}"));
+    }
+    let path = manifest_path(root, direct);
+    let old = fs::read_to_string(&path).unwrap_or_default();
+    let recs: Vec<&str> = std::iter::once(rec.as_str())
+        .chain(
+            old.split("\n\n")
+                .filter(|r| !r.is_empty() && r.lines().next() != Some(key)),
+        )
+        .take(MANIFEST_RECORDS)
+        .collect();
+    let tmp = root
+        .join("tmp")
+        .join(format!("{direct}.{}", std::process::id()));
+    let res = (|| -> std::io::Result<()> {
+        fs::create_dir_all(root.join("tmp"))?;
+        fs::write(&tmp, recs.join("\n\n"))?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::rename(&tmp, &path)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+// `-l<name>` (or `-l:<file>`) as the linker finds it: the first `-L` dir that has the
+// library, shared before static. Libraries in none of them come from the toolchain's
+// own search path and are covered, like its headers, by the compiler id.
+fn link_libraries(args: &[&str]) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let mut libs = vec![];
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        for (flag, list) in [("-L", &mut dirs), ("-l", &mut libs)] {
+            if let Some(v) = a.strip_prefix(flag) {
+                match v {
+                    "" => list.extend(it.next().copied()),
+                    v => list.push(v),
+                }
+                break;
+            }
+        }
+    }
+    libs.into_iter()
+        .filter_map(|l| {
+            let files = match l.strip_prefix(':') {
+                Some(f) => vec![f.to_string()],
+                None => ["so", "dylib", "a"]
+                    .iter()
+                    .map(|ext| format!("lib{l}.{ext}"))
+                    .collect(),
+            };
+            dirs.iter().find_map(|d| {
+                files
+                    .iter()
+                    .map(|f| Path::new(d).join(f))
+                    .find(|p| p.is_file())
+            })
+        })
+        .collect()
+}
+
+// Links are keyed by the contents of the files on their command line and of the
+// libraries `-L`/`-l` resolve to, along with where those were found.
+fn hash_link_inputs(h: &mut Sha256, args: &[&str]) {
+    for f in args {
+        if let Ok(bytes) = fs::read(f) {
+            h.update(Sha256::digest(&bytes));
+        }
+    }
+    for lib in link_libraries(args) {
+        h.update(lib.to_string_lossy().as_bytes());
+        h.update([0]);
+        if let Ok(bytes) = fs::read(&lib) {
+            h.update(Sha256::digest(&bytes));
+        }
+    }
+}
+
+/// A cached action result: the output file, the headers for its depfile and the
+/// compiler's diagnostics.
+struct Entry {
+    obj: Vec<u8>,
+    deps: String,
+    stderr: Vec<u8>,
+}
+
+const BUNDLE_MAGIC: &[u8] = b"ghost-entry-1\n";
+
+impl Entry {
+    fn load(entry: &Path) -> Option<Entry> {
+        Some(Entry {
+            obj: fs::read(entry.join("obj")).ok()?,
+            deps: fs::read_to_string(entry.join("deps")).unwrap_or_default(),
+            stderr: fs::read(entry.join("stderr")).unwrap_or_default(),
+        })
+    }
+
+    // written under a temporary name first so readers never see a partial entry
+    fn store(&self, root: &Path, key: &str) -> Result<()> {
+        let entry = entry_dir(root, key);
+        let tmp = root
+            .join("tmp")
+            .join(format!("{}.{}", key, std::process::id()));
+        let res = (|| -> Result<()> {
+            fs::create_dir_all(&tmp)?;
+            fs::write(tmp.join("obj"), &self.obj)?;
+            fs::write(tmp.join("deps"), &self.deps)?;
+            fs::write(tmp.join("stderr"), &self.stderr)?;
+            fs::create_dir_all(entry.parent().unwrap())?;
+            fs::rename(&tmp, &entry)?;
+            Ok(())
+        })();
+        if res.is_err() {
+            let _ = fs::remove_dir_all(&tmp);
+        }
+        res
+    }
+
+    /// Single-blob form for the remote cache: magic, then each part as a
+    /// little-endian u64 length followed by its bytes.
+    fn to_bundle(&self) -> Vec<u8> {
+        let mut b = BUNDLE_MAGIC.to_vec();
+        for part in [&self.obj[..], self.deps.as_bytes(), &self.stderr[..]] {
+            b.extend((part.len() as u64).to_le_bytes());
+            b.extend(part);
+        }
+        b
+    }
+
+    fn from_bundle(b: &[u8]) -> Option<Entry> {
+        let mut rest = b.strip_prefix(BUNDLE_MAGIC)?;
+        let mut parts = vec![];
+        for _ in 0..3 {
+            let (len, tail) = rest.split_at_checked(8)?;
+            let len = u64::from_le_bytes(len.try_into().ok()?) as usize;
+            let (part, tail) = tail.split_at_checked(len)?;
+            parts.push(part.to_vec());
+            rest = tail;
+        }
+        let stderr = parts.pop()?;
+        let deps = String::from_utf8(parts.pop()?).ok()?;
+        Some(Entry {
+            obj: parts.pop()?,
+            deps,
+            stderr,
+        })
+    }
+
+    fn restore(&self, a: &Action, link: bool) -> Result<()> {
+        fs::write(a.out, &self.obj).with_context(|| format!("write {}", a.out))?;
+        #[cfg(unix)]
+        if link {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(a.out, fs::Permissions::from_mode(0o755))?;
+        }
+        #[cfg(not(unix))]
+        let _ = link;
+        if let Some(d) = a.depfile {
+            write_depfile(d, a.out, &self.deps)?;
+        }
+        let _ = std::io::stderr().write_all(&self.stderr);
+        Ok(())
+    }
+}
+
+// `h` local hit, `r` remote hit, `m` miss
+fn record(log: Option<&str>, what: u8) {
+    if let Some(log) = log {
+        if let Ok(mut f) = fs::OpenOptions::new().create(true).append(true).open(log) {
+            let _ = f.write_all(&[what]);
+        }
+    }
+}
+
+fn write_depfile(path: &str, out: &str, deps: &str) -> Result<()> {
+    let deps: Vec<String> = deps.lines().map(|d| d.replace(' ', "\\ ")).collect();
+    fs::write(path, format!("{}: {}\n", out, deps.join(" \\\n  ")))
+        .with_context(|| format!("write {path}"))
+}
+
+fn remote_down_marker(log: Option<&str>) -> Option<PathBuf> {
+    log.map(|l| PathBuf::from(format!("{l}{REMOTE_DOWN_SUFFIX}")))
+}
+
+/// `ghost cache-exec --dir <cas> [--log <file>] [--remote <url> [--remote-write]]
+/// -- <compiler> <args...>`: the compile/link launcher. Returns the exit code to use.
+pub fn exec(args: &[String]) -> Result<i32> {
+    let mut dir = None;
+    let mut log = None;
+    let mut remote_url = None;
+    let mut remote_write = false;
+    let mut it = args.iter();
+    let mut argv: Vec<String> = vec![];
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "--dir" => dir = it.next().map(PathBuf::from),
+            "--log" => log = it.next().cloned(),
+            "--remote" => remote_url = it.next().cloned(),
+            "--remote-write" => remote_write = true,
+            "--" => {
+                argv = it.cloned().collect();
+                break;
+            }
+            other => bail!("cache-exec: unexpected argument '{other}'"),
+        }
+    }
+    let Some((compiler, rest)) = argv.split_first() else {
+        bail!("cache-exec: missing compiler command after --");
+    };
+    let dir = dir.unwrap_or_else(cache_dir);
+    let run = |args: &[String]| -> Result<std::process::Output> {
+        Command::new(compiler)
+            .args(args)
+            .output()
+            .with_context(|| format!("run {compiler}"))
+    };
+    let replay = |out: &std::process::Output| {
+        let _ = std::io::stdout().write_all(&out.stdout);
+        let _ = std::io::stderr().write_all(&out.stderr);
+        out.status.code().unwrap_or(1)
+    };
+
+    let Some(action) = split_action(rest) else {
+        return Ok(replay(&run(rest)?));
+    };
+    let link = action.preprocess.is_none();
+    let command = command_hash(compiler, &action);
+
+    // Compiles that write a depfile are looked up in direct mode first. They run with
+    // -MD so that the depfile, and so the manifest, lists system headers too.
+    let direct = (!link && action.depfile.is_some()).then(|| {
+        let mut h = command.clone();
+        h.update(b"direct");
+        hex::encode(h.finalize())
+    });
+    let args: Vec<String> = match direct {
+        Some(_) => rest
+            .iter()
+            .map(|a| if a == "-MMD" { "-MD".into() } else { a.clone() })
+            .collect(),
+        None => rest.to_vec(),
+    };
+    let hit = |e: Entry, key: &str| -> Result<i32> {
+        e.restore(&action, link)?;
+        // entries are evicted least recently used first
+        if let Ok(f) = fs::File::open(entry_dir(&dir, key).join("obj")) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+        record(log.as_deref(), b'h');
+        Ok(0)
+    };
+    if let Some(key) = direct.as_deref().and_then(|d| manifest_lookup(&dir, d)) {
+        if let Some(e) = Entry::load(&entry_dir(&dir, &key)) {
+            return hit(e, &key);
+        }
+    }
+
+    // once the remote cache failed, the rest of the build uses only the local one
+    let marker = remote_down_marker(log.as_deref());
+    let remote = remote_url
+        .filter(|_| !marker.as_ref().is_some_and(|m| m.exists()))
+        .map(|u| Remote::parse(&u))
+        .transpose()?;
+    let remote_failed = |e: anyhow::Error| {
+        if let Some(m) = &marker {
+            let _ = fs::write(m, format!("{e:#}"));
+        }
+    };
+
+    // Without a remote cache, a direct-mode miss is keyed by the files its depfile
+    // lists once it has compiled. Sharing entries between machines needs a key
+    // known before compiling, which costs a run of the preprocessor on every miss.
+    let mut h = command;
+    let key = match &action.preprocess {
+        None => {
+            hash_link_inputs(&mut h, &action.normalized);
+            Some(hex::encode(h.finalize()))
+        }
+        Some(_) if direct.is_some() && remote.is_none() => None,
+        Some(pre) => match Command::new(compiler).args(pre).output() {
+            Ok(p) if p.status.success() => {
+                h.update(&p.stdout);
+                Some(hex::encode(h.finalize()))
+            }
+            // let the real compile report the error
+            _ => {
+                record(log.as_deref(), b'm');
+                return Ok(replay(&run(&args)?));
+            }
+        },
+    };
+    // the files a direct-mode compile read, as they are now
+    let digests = |deps: &str, started: SystemTime| {
+        direct.as_ref()?;
+        file_digests(&deps.lines().collect::<Vec<_>>(), started)
+    };
+    let remember = |key: &str, files: Option<Vec<(String, String)>>| {
+        if let (Some(d), Some(files)) = (&direct, files) {
+            manifest_add(&dir, d, key, &files);
+        }
+    };
+
+    if let Some(key) = &key {
+        if let Some(e) = Entry::load(&entry_dir(&dir, key)) {
+            remember(key, digests(&e.deps, SystemTime::now()));
+            return hit(e, key);
+        }
+        if let Some(r) = &remote {
+            match r.get("ac", key) {
+                Ok(Some(bundle)) => {
+                    if let Some(e) = Entry::from_bundle(&bundle) {
+                        e.restore(&action, link)?;
+                        let _ = e.store(&dir, key);
+                        remember(key, digests(&e.deps, SystemTime::now()));
+                        record(log.as_deref(), b'r');
+                        return Ok(0);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => remote_failed(e),
+            }
+        }
+    }
+
+    let started = SystemTime::now();
+    let out = run(&args)?;
+    record(log.as_deref(), b'm');
+    if out.status.success() {
+        if let Ok(obj) = fs::read(action.out) {
+            let e = Entry {
+                obj,
+                deps: action
+                    .depfile
+                    .and_then(|d| fs::read_to_string(d).ok())
+                    .map(|t| parse_depfile(&t).join("\n"))
+                    .unwrap_or_default(),
+                stderr: out.stderr.clone(),
+            };
+            let files = digests(&e.deps, started);
+            let key = key.or_else(|| Some(deps_key(direct.as_deref()?, files.as_deref()?)));
+            if let Some(key) = key {
+                let _ = e.store(&dir, &key);
+                remember(&key, files);
+                if let Some(r) = remote.as_ref().filter(|_| remote_write) {
+                    if let Err(err) = r.put("ac", &key, &e.to_bundle()) {
+                        remote_failed(err);
+                    }
+                }
+            }
+        }
+    }
+    Ok(replay(&out))
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Totals {
+    hits: u64,
+    misses: u64,
+}
+
+fn load_totals(dir: &Path) -> Totals {
+    fs::read_to_string(dir.join(STATS_FILE))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Reads and removes the per-build hit/miss log, prints the counts and adds them
+/// to the totals kept in the cache directory.
+pub fn report_build(build_dir: &str, remote: Option<&RemoteConfig>) {
+    let path = Path::new(build_dir).join(BUILD_LOG);
+    let marker = PathBuf::from(format!("{}{}", path.display(), REMOTE_DOWN_SUFFIX));
+    if let (Some(r), Ok(why)) = (remote, fs::read_to_string(&marker)) {
+        eprintln!(
+            "warning: remote cache {} unavailable ({}), used the local cache only",
+            r.url,
+            why.trim()
+        );
+    }
+    let _ = fs::remove_file(&marker);
+    let Ok(log) = fs::read(&path) else { return };
+    let _ = fs::remove_file(&path);
+    let count = |c: u8| log.iter().filter(|&&b| b == c).count() as u64;
+    let (local, remote_hits, misses) = (count(b'h'), count(b'r'), count(b'm'));
+    let hits = local + remote_hits;
+    if hits + misses == 0 {
+        return;
+    }
+    let remote_note = if remote.is_some() {
+        format!(" ({} remote)", remote_hits)
+    } else {
+        String::new()
+    };
+    ui::status!(
+        "cache: {} hit(s){}, {} miss(es) ({:.0}% hit rate)",
+        hits,
+        remote_note,
+        misses,
+        100.0 * hits as f64 / (hits + misses) as f64
+    );
+    let dir = cache_dir();
+    let mut t = load_totals(&dir);
+    t.hits += hits;
+    t.misses += misses;
+    if fs::create_dir_all(&dir).is_ok() {
+        let _ = fs::write(
+            dir.join(STATS_FILE),
+            serde_json::to_string(&t).unwrap_or_default(),
+        );
+    }
+}
+
+// (entry dir, size in bytes, last use)
+fn entries(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let mut out = vec![];
+    let Ok(shards) = fs::read_dir(dir) else {
+        return out;
+    };
+    for shard in shards.flatten() {
+        let name = shard.file_name();
+        if name.len() != 2 || !shard.path().is_dir() {
+            continue;
+        }
+        for e in fs::read_dir(shard.path()).into_iter().flatten().flatten() {
+            let mut size = 0;
+            for f in fs::read_dir(e.path()).into_iter().flatten().flatten() {
+                size += f.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+            let used = fs::metadata(e.path().join("obj"))
+                .and_then(|m| m.modified())
+                .unwrap_or(UNIX_EPOCH);
+            out.push((e.path(), size, used));
+        }
+    }
+    out
+}
+
+fn human(bytes: u64) -> String {
+    let mut v = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if v < 1024.0 || unit == "GiB" {
+            return format!("{:.1} {}", v, unit);
+        }
+        v /= 1024.0;
+    }
+    unreachable!()
+}
+
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => {
+            let mult: u64 = match s[i..]
+                .to_ascii_uppercase()
+                .trim_end_matches("IB")
+                .trim_end_matches('B')
+            {
+                "" => 1,
+                "K" => 1 << 10,
+                "M" => 1 << 20,
+                "G" => 1 << 30,
+                "T" => 1 << 40,
+                other => bail!("unknown size unit '{other}' in '{s}'"),
+            };
+            (&s[..i], mult)
+        }
+        None => (s, 1),
+    };
+    let n: f64 = num
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid size '{s}'"))?;
+    Ok((n * mult as f64) as u64)
+}
+
+/// `ghost cache stats`
+pub fn stats() -> Result<()> {
+    let dir = cache_dir();
+    let e = entries(&dir);
+    let size: u64 = e.iter().map(|(_, s, _)| s).sum();
+    let t = load_totals(&dir);
+    println!("cache dir: {}", dir.display());
+    println!("entries:   {}", e.len());
+    println!("size:      {}", human(size));
+    println!("hits:      {}", t.hits);
+    println!("misses:    {}", t.misses);
+    if t.hits + t.misses > 0 {
+        println!(
+            "hit rate:  {:.1}%",
+            100.0 * t.hits as f64 / (t.hits + t.misses) as f64
+        );
+    }
+    Ok(())
+}
+
+/// `ghost cache gc [--max-size <size>]`
+pub fn gc(max_size: Option<&str>) -> Result<()> {
+    let dir = cache_dir();
+    let max = match max_size {
+        Some(s) => parse_size(s)?,
+        None => match env::var("GHOST_CACHE_MAX_SIZE") {
+            Ok(v) => parse_size(&v).context("GHOST_CACHE_MAX_SIZE")?,
+            Err(_) => DEFAULT_MAX_SIZE,
+        },
+    };
+    let mut e = entries(&dir);
+    e.sort_by_key(|(_, _, used)| *used);
+    let mut size: u64 = e.iter().map(|(_, s, _)| s).sum();
+    let mut removed = 0usize;
+    for (path, s, _) in &e {
+        if size <= max {
+            break;
+        }
+        fs::remove_dir_all(path).with_context(|| format!("remove {}", path.display()))?;
+        size -= s;
+        removed += 1;
+    }
+    let _ = fs::remove_dir_all(dir.join("tmp"));
+    println!(
+        "removed {} entr{}, {} left (limit {})",
+        removed,
+        if removed == 1 { "y" } else { "ies" },
+        human(size),
+        human(max)
+    );
+    Ok(())
+}
+
+/// `ghost cache clear`
+pub fn clear() -> Result<()> {
+    let dir = cache_dir();
+    if dir.exists() {
+        fs::remove_dir_all(&dir).with_context(|| format!("remove {}", dir.display()))?;
+    }
+    println!("cleared {}", dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let d = env::temp_dir().join(format!("ghost-cache-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&d);
+        fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    fn link_key(args: &[&str]) -> String {
+        let mut h = Sha256::new();
+        hash_link_inputs(&mut h, args);
+        hex::encode(h.finalize())
+    }
+
+    fn words(cmd: &str) -> Vec<String> {
+        cmd.split(' ').map(String::from).collect()
+    }
+
+    fn command_key(cmd: &str) -> String {
+        let argv = words(cmd);
+        hex::encode(command_hash("cc", &split_action(&argv).unwrap()).finalize())
+    }
+
+    #[test]
+    fn outputs_are_left_out_of_the_key() {
+        let argv = words("-MMD -MF obj/a.o.d -DX=1 -c a.c -o obj/a.o");
+        let a = split_action(&argv).unwrap();
+        assert_eq!((a.out, a.depfile), ("obj/a.o", Some("obj/a.o.d")));
+        assert_eq!(a.normalized, ["-MMD", "-DX=1", "-c", "a.c"]);
+        assert_eq!(a.preprocess.unwrap(), ["-DX=1", "a.c", "-E"]);
+        assert_eq!(
+            command_key("-c a.c -o debug/a.o"),
+            command_key("-c a.c -o release/a.o")
+        );
+
+        let link = words("a.o b.o -o app -lm");
+        assert!(split_action(&link).unwrap().preprocess.is_none());
+        assert!(split_action(&words("-c a.c")).is_none());
+    }
+
+    #[test]
+    fn defines_change_the_key() {
+        assert_ne!(
+            command_key("-DX=1 -c a.c -o a.o"),
+            command_key("-DX=2 -c a.c -o a.o")
+        );
+    }
+
+    #[test]
+    fn direct_mode_misses_when_a_header_changes() {
+        let d = scratch("direct");
+        let (src, hdr) = (d.join("a.c"), d.join("a.h"));
+        fs::write(&src, "#include \"a.h\"\n").unwrap();
+        fs::write(&hdr, "#define X 1\n").unwrap();
+        let files = [src.to_str().unwrap(), hdr.to_str().unwrap()];
+        let direct = "ab".repeat(32);
+
+        let before = file_digests(&files, SystemTime::now()).unwrap();
+        manifest_add(&d, &direct, "k1", &before);
+        assert_eq!(manifest_lookup(&d, &direct).as_deref(), Some("k1"));
+
+        fs::write(&hdr, "#define X 2\n").unwrap();
+        assert_eq!(manifest_lookup(&d, &direct), None);
+        let after = file_digests(&files, SystemTime::now()).unwrap();
+        assert_ne!(deps_key(&direct, &before), deps_key(&direct, &after));
+        manifest_add(&d, &direct, "k2", &after);
+
+        // both versions stay known
+        assert_eq!(manifest_lookup(&d, &direct).as_deref(), Some("k2"));
+        fs::write(&hdr, "#define X 1\n").unwrap();
+        assert_eq!(manifest_lookup(&d, &direct).as_deref(), Some("k1"));
+        // a file written during the compile is not trusted
+        assert!(file_digests(&files, UNIX_EPOCH).is_none());
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let e = Entry {
+            obj: vec![0, 1, 2, 255],
+            deps: "a.c\ninclude/a b.h".into(),
+            stderr: b"warning: unused\n".to_vec(),
+        };
+        let b = e.to_bundle();
+        let back = Entry::from_bundle(&b).unwrap();
+        assert_eq!(
+            (back.obj, back.deps, back.stderr),
+            (e.obj, e.deps, e.stderr)
+        );
+        assert!(Entry::from_bundle(&b[..b.len() - 1]).is_none());
+        assert!(Entry::from_bundle(&b[1..]).is_none());
+    }
+
+    #[test]
+    fn libraries_resolve_through_link_dirs() {
+        let d = scratch("libs");
+        let (first, second) = (d.join("first"), d.join("second"));
+        for dir in [&first, &second] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(first.join("libz.a"), "static").unwrap();
+        fs::write(second.join("libz.so"), "shared").unwrap();
+        fs::write(second.join("libpng.a"), "png").unwrap();
+        fs::write(second.join("libpng.so"), "png").unwrap();
+        let (l1, l2) = (
+            format!("-L{}", first.display()),
+            second.display().to_string(),
+        );
+        let args = ["a.o", &l1, "-L", &l2, "-lz", "-l", "png", "-lm"];
+        assert_eq!(
+            link_libraries(&args),
+            [first.join("libz.a"), second.join("libpng.so")]
+        );
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn link_key_follows_library_contents() {
+        let d = scratch("relink");
+        fs::write(d.join("libadd.a"), "v1").unwrap();
+        let dir = format!("-L{}", d.display());
+        let args = ["main.o", &dir, "-ladd"];
+        let before = link_key(&args);
+        assert_eq!(link_key(&args), before);
+        fs::write(d.join("libadd.a"), "v2").unwrap();
+        assert_ne!(link_key(&args), before);
+        let _ = fs::remove_dir_all(&d);
+    }
+}
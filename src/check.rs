@@ -0,0 +1,486 @@
+use crate::context::Ctx;
+use crate::discover;
+use crate::error::GhostError;
+use crate::graph::{self, Graph, PkgNode};
+use crate::manifest::load_package_manifest;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+const DEFAULT_INCLUDE: &[&str] = &["**/*.c", "**/*.cc", "**/*.cpp", "**/*.cxx"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// The manifest at fault.
+    pub file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Problem {
+    fn error(kind: &'static str, node: &PkgNode, message: String) -> Problem {
+        Problem {
+            severity: Severity::Error,
+            kind,
+            package: Some(node.name.clone()),
+            file: node.manifest_path(),
+            line: None,
+            column: None,
+            message,
+        }
+    }
+
+    fn warning(kind: &'static str, node: &PkgNode, message: String) -> Problem {
+        Problem {
+            severity: Severity::Warning,
+            ..Problem::error(kind, node, message)
+        }
+    }
+
+    fn from_ghost(e: &GhostError, file: String, package: Option<String>) -> Problem {
+        let at = e.location().filter(|at| at.line > 0);
+        Problem {
+            severity: Severity::Error,
+            kind: e.kind(),
+            package,
+            file: e.location().map_or(file, |at| at.path.clone()),
+            line: at.map(|at| at.line),
+            column: at.map(|at| at.col),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Validates every member of the workspace without generating anything, collecting
+/// all problems instead of stopping at the first.
+pub fn check(ctx: &Ctx, members: &[String]) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut nodes = vec![];
+    // members whose manifest failed to load; depending on them is not another error
+    let mut broken = HashSet::new();
+    for m in members {
+        let path = Path::new(m).join("ghost.build");
+        if !path.is_file() {
+            let e = GhostError::MissingManifest { member: m.clone() };
+            problems.push(Problem::from_ghost(&e, "ghost.build".into(), None));
+            continue;
+        }
+        let path = path.to_string_lossy().to_string();
+        match load_package_manifest(&path) {
+            Ok(manifest) => nodes.push(PkgNode {
+                name: manifest.package.name.clone(),
+                root: PathBuf::from(m).canonicalize().unwrap_or_else(|_| m.into()),
+                manifest,
+            }),
+            Err(e) => {
+                broken.extend(package_name(&path));
+                problems.push(match e.downcast_ref::<GhostError>() {
+                    Some(g) => Problem::from_ghost(g, path, None),
+                    None => Problem {
+                        severity: Severity::Error,
+                        kind: "manifest",
+                        package: None,
+                        file: path,
+                        line: None,
+                        column: None,
+                        message: format!("{e:#}"),
+                    },
+                })
+            }
+        }
+    }
+
+    for node in &nodes {
+        check_files(ctx, node, &nodes, &mut problems);
+    }
+
+    let invalid = graph::validate(&nodes);
+    let by_name: HashMap<&str, &PkgNode> = nodes.iter().map(|n| (n.name.as_str(), n)).collect();
+    let on_broken = |e: &GhostError| match e {
+        GhostError::UnknownDependency { dep, .. } => broken.contains(dep),
+        _ => false,
+    };
+    for e in invalid.iter().filter(|e| !on_broken(e)) {
+        let (file, package) = match e {
+            GhostError::DuplicatePackage { second, name, .. } => (second.clone(), Some(name)),
+            GhostError::UnknownDependency {
+                manifest, package, ..
+            }
+            | GhostError::BadDependency {
+                manifest, package, ..
+            } => (manifest.clone(), Some(package)),
+            _ => ("ghost.build".into(), None),
+        };
+        problems.push(Problem::from_ghost(e, file, package.cloned()));
+    }
+    for node in &nodes {
+        check_unused_deps(node, &by_name, &mut problems);
+    }
+    if invalid.is_empty() {
+        if let Err(e) = Graph::new(nodes) {
+            if let Some(g) = e.downcast_ref::<GhostError>() {
+                problems.push(Problem::from_ghost(g, "ghost.build".into(), None));
+            }
+        }
+    }
+    problems
+}
+
+// `package.name` of a manifest that parses as TOML but was rejected otherwise.
+fn package_name(path: &str) -> Option<String> {
+    let table: toml::Table = fs::read_to_string(path).ok()?.parse().ok()?;
+    Some(table.get("package")?.get("name")?.as_str()?.to_string())
+}
+
+fn check_files(ctx: &Ctx, node: &PkgNode, nodes: &[PkgNode], problems: &mut Vec<Problem>) {
+    let pkg = &node.manifest;
+    let listed: HashSet<String> = pkg
+        .sources
+        .files
+        .iter()
+        .map(|f| f.trim_start_matches("./").replace('\\', "/"))
+        .collect();
+
+    let generated = pkg.generated();
+    for f in &pkg.sources.files {
+        if !generated.contains(f) && !node.root.join(f).exists() {
+            problems.push(Problem::error(
+                "missing-source",
+                node,
+                format!("source '{f}' does not exist"),
+            ));
+        }
+    }
+    for f in pkg.generate.iter().flat_map(|g| &g.inputs) {
+        if !generated.contains(f) && !node.root.join(f).exists() {
+            problems.push(Problem::error(
+                "missing-source",
+                node,
+                format!("[[generate]] input '{f}' does not exist"),
+            ));
+        }
+    }
+
+    for (section, pp) in [("public", &pkg.public), ("private", &pkg.private)] {
+        for d in pp
+            .as_ref()
+            .and_then(|p| p.include_dirs.as_ref())
+            .into_iter()
+            .flatten()
+        {
+            if !node.root.join(d).is_dir() {
+                problems.push(Problem::error(
+                    "missing-include-dir",
+                    node,
+                    format!("[{section}] include dir '{d}' does not exist"),
+                ));
+            }
+        }
+    }
+
+    let include: Vec<String> = if pkg.sources.include.is_empty() {
+        DEFAULT_INCLUDE.iter().map(|s| s.to_string()).collect()
+    } else {
+        pkg.sources.include.clone()
+    };
+    for p in &include {
+        if let Err(source) = globset::Glob::new(p) {
+            let e = GhostError::InvalidGlob {
+                origin: "[sources] include".into(),
+                source,
+            };
+            problems.push(Problem::from_ghost(
+                &e,
+                node.manifest_path(),
+                Some(node.name.clone()),
+            ));
+            return;
+        }
+    }
+    // members nested inside this package list their own files
+    let nested: Vec<String> = nodes
+        .iter()
+        .filter(|n| n.root != node.root && n.root.starts_with(&node.root))
+        .filter_map(|n| n.root.strip_prefix(&node.root).ok())
+        .map(|rel| format!("{}/**", rel.to_string_lossy().replace('\\', "/")))
+        .collect();
+    let root = node.root.to_string_lossy();
+    match discover::discover(ctx, &root, &[".".into()], &include, &nested) {
+        Ok(found) => {
+            for f in found.files {
+                let f = f.trim_start_matches("./").to_string();
+                if !listed.contains(&f) {
+                    problems.push(Problem::warning(
+                        "unlisted-file",
+                        node,
+                        format!("'{f}' is not listed in [sources] files"),
+                    ));
+                }
+            }
+        }
+        Err(e) => problems.push(Problem::error(
+            "discover",
+            node,
+            format!("scanning for sources: {e:#}"),
+        )),
+    }
+}
+
+// Headers a dependent can include from `node`: everything under its public include
+// dirs (and the implicit `include/`), relative to those dirs, and what its
+// `[[generate]]` steps will write to `.gen/`.
+fn public_headers(node: &PkgNode) -> Vec<String> {
+    let mut dirs: Vec<String> = node
+        .manifest
+        .public
+        .as_ref()
+        .and_then(|p| p.include_dirs.clone())
+        .unwrap_or_default();
+    dirs.push("include".into());
+    let mut out: Vec<String> = node
+        .manifest
+        .generate
+        .iter()
+        .flat_map(|g| g.outputs.iter().cloned())
+        .collect();
+    for d in dirs {
+        let base = node.root.join(d);
+        for e in WalkDir::new(&base).into_iter().filter_map(|e| e.ok()) {
+            if e.file_type().is_file() {
+                if let Ok(rel) = e.path().strip_prefix(&base) {
+                    out.push(rel.to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+    }
+    out
+}
+
+// `#include "x"` / `#include <x>` targets in the package's sources and own headers.
+fn included_paths(node: &PkgNode) -> HashSet<String> {
+    let mut files: Vec<PathBuf> = node
+        .manifest
+        .sources
+        .files
+        .iter()
+        .map(|f| node.root.join(f))
+        .collect();
+    let mut dirs = vec!["include".to_string(), "src".to_string()];
+    for pp in [&node.manifest.public, &node.manifest.private] {
+        dirs.extend(
+            pp.as_ref()
+                .and_then(|p| p.include_dirs.clone())
+                .unwrap_or_default(),
+        );
+    }
+    for d in dirs {
+        files.extend(
+            WalkDir::new(node.root.join(d))
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.into_path()),
+        );
+    }
+    let mut out = HashSet::new();
+    for f in files {
+        let Ok(text) = fs::read_to_string(&f) else {
+            continue;
+        };
+        for line in text.lines() {
+            let Some(rest) = line.trim_start().strip_prefix('#') else {
+                continue;
+            };
+            let Some(rest) = rest.trim_start().strip_prefix("include") else {
+                continue;
+            };
+            let rest = rest.trim();
+            let close = match rest.chars().next() {
+                Some('"') => '"',
+                Some('<') => '>',
+                _ => continue,
+            };
+            if let Some(end) = rest[1..].find(close) {
+                out.insert(rest[1..=end].to_string());
+            }
+        }
+    }
+    out
+}
+
+// A dependency is unused if none of its headers (or those it passes on through its
+// own `deps.direct`) are included. Dependencies without headers are not judged.
+fn check_unused_deps(
+    node: &PkgNode,
+    by_name: &HashMap<&str, &PkgNode>,
+    problems: &mut Vec<Problem>,
+) {
+    let deps: Vec<&String> = node.direct().iter().chain(node.private()).collect();
+    if deps.is_empty() {
+        return;
+    }
+    let included = included_paths(node);
+    for d in deps {
+        let mut headers = vec![];
+        let mut seen = HashSet::new();
+        let mut todo = vec![d.as_str()];
+        while let Some(name) = todo.pop() {
+            let Some(dep) = by_name.get(name) else {
+                continue;
+            };
+            if seen.insert(name) {
+                headers.extend(public_headers(dep));
+                todo.extend(dep.direct().iter().map(String::as_str));
+            }
+        }
+        if headers.is_empty() {
+            continue;
+        }
+        let used = included.iter().any(|inc| {
+            headers
+                .iter()
+                .any(|h| inc == h || inc.ends_with(&format!("/{h}")))
+        });
+        if !used {
+            problems.push(Problem::warning(
+                "unused-dependency",
+                node,
+                format!("dependency '{d}' is not used: none of its headers are included"),
+            ));
+        }
+    }
+}
+
+pub fn print_human(problems: &[Problem], packages: usize) {
+    for p in problems {
+        let pos = match (p.line, p.column) {
+            (Some(l), Some(c)) => format!(":{l}:{c}"),
+            _ => String::new(),
+        };
+        let sev = match p.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        println!("{}{}: {}: {} [{}]", p.file, pos, sev, p.message, p.kind);
+    }
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    println!(
+        "checked {} package(s): {} error(s), {} warning(s)",
+        packages,
+        errors,
+        problems.len() - errors
+    );
+}
+
+pub fn print_json(problems: &[Problem]) -> serde_json::Result<()> {
+    #[derive(Serialize)]
+    struct Report<'a> {
+        errors: usize,
+        warnings: usize,
+        problems: &'a [Problem],
+    }
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    let report = Report {
+        errors,
+        warnings: problems.len() - errors,
+        problems,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A workspace in a scratch dir with one member per `(dir, ghost.build)`; each
+    // member gets the sources its manifest lists. Returns the member paths.
+    fn workspace(name: &str, members: &[(&str, &str)]) -> (PathBuf, Vec<String>) {
+        let d = std::env::temp_dir().join(format!("ghost-check-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&d);
+        let mut paths = vec![];
+        for (dir, manifest) in members {
+            let root = d.join(dir);
+            fs::create_dir_all(&root).unwrap();
+            if !manifest.is_empty() {
+                fs::write(root.join("ghost.build"), manifest).unwrap();
+                fs::write(root.join(format!("{dir}.c")), "").unwrap();
+            }
+            paths.push(root.display().to_string());
+        }
+        (d, paths)
+    }
+
+    fn kinds(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|p| p.kind).collect()
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let (d, members) = workspace(
+            "all",
+            &[
+                ("empty", ""),
+                (
+                    "app",
+                    "[package]\nname = \"app\"\ntype = \"exe\"\n\
+                     [sources]\nfiles = [\"app.c\", \"gone.c\"]\n\
+                     [deps]\ndirect = [\"nope\"]\n",
+                ),
+            ],
+        );
+        let problems = check(&Ctx::default(), &members);
+        assert_eq!(
+            kinds(&problems),
+            ["missing-manifest", "missing-source", "unknown-dependency"]
+        );
+        assert!(problems.iter().all(|p| p.severity == Severity::Error));
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn dependents_of_a_broken_member_are_not_reported() {
+        let (d, members) = workspace(
+            "broken",
+            &[
+                (
+                    "add",
+                    "[package]\nname = \"add\"\ntype = \"statik\"\n[sources]\nfiles = [\"add.c\"]\n",
+                ),
+                (
+                    "app",
+                    "[package]\nname = \"app\"\ntype = \"exe\"\n[sources]\nfiles = [\"app.c\"]\n\
+                     [deps]\ndirect = [\"add\"]\n",
+                ),
+            ],
+        );
+        let problems = check(&Ctx::default(), &members);
+        assert_eq!(kinds(&problems), ["bad-package-type"]);
+        assert_eq!(problems[0].line, Some(3));
+        let _ = fs::remove_dir_all(&d);
+    }
+}
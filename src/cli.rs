@@ -0,0 +1,238 @@
+use crate::executor::Executor;
+use crate::manifest::{Lto, ProfileFrag};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// Ghost – minimal build orchestrator for C/C++.
+#[derive(Debug, Parser)]
+#[command(name = "ghost", version, propagate_version = true)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalOpts,
+    #[command(subcommand)]
+    pub cmd: Cmd,
+}
+
+#[derive(Debug, Args)]
+pub struct GlobalOpts {
+    /// Run as if ghost was started in <DIR>
+    #[arg(short = 'C', value_name = "DIR", global = true)]
+    pub directory: Option<PathBuf>,
+    /// Toolchain file (default: $GHOST_PROFILE, else built-in clang)
+    #[arg(long, value_name = "FILE", global = true)]
+    pub profile: Option<String>,
+    /// Build configuration: debug, release, relwithdebinfo or a [profile.<name>]
+    #[arg(long, value_name = "NAME", alias = "profile-name", global = true)]
+    pub config: Option<String>,
+    /// Build directory (overrides [builddir] in ghost.build)
+    #[arg(long, value_name = "DIR", global = true)]
+    pub builddir: Option<String>,
+    /// Parallel jobs for the executor and the test runner
+    #[arg(short, long, value_name = "N", global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+    /// Print the commands being run
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    pub verbose: bool,
+    /// Only print warnings and errors
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Cmd {
+    /// Generate build.ninja and build the workspace
+    Build {
+        #[command(flatten)]
+        build: BuildArgs,
+        /// Toolchain file (legacy form of --profile)
+        #[arg(hide = true)]
+        legacy_profile: Option<String>,
+    },
+    /// Build the workspace and run its test packages
+    Test {
+        #[command(flatten)]
+        build: BuildArgs,
+        /// Only run tests whose name contains one of these strings
+        filters: Vec<String>,
+        /// Per-test timeout in seconds (0 disables it)
+        #[arg(long, value_name = "SECS", default_value_t = 60)]
+        timeout: u64,
+        /// Write a JUnit XML report
+        #[arg(long, value_name = "FILE")]
+        junit: Option<PathBuf>,
+    },
+    /// Build one exe package (and its dependencies) and run it
+    Run {
+        /// Package to run; may be omitted if the workspace has a single exe
+        package: Option<String>,
+        #[command(flatten)]
+        build: BuildArgs,
+        /// Working directory for the program
+        #[arg(long, value_name = "DIR")]
+        cwd: Option<PathBuf>,
+        /// Arguments passed to the program
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Validate the whole workspace without building and report every problem
+    #[command(visible_alias = "discover")]
+    Check {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = Format::Human)]
+        format: Format,
+    },
+    /// Remove build outputs
+    Clean {
+        /// Only remove obj/<name> and the package's .ghost/ (repeatable)
+        #[arg(short, long = "package", value_name = "NAME")]
+        packages: Vec<String>,
+        /// Also remove compile_commands.json and the object cache
+        #[arg(long, conflicts_with = "packages")]
+        all: bool,
+    },
+    /// Inspect or prune the local object cache
+    Cache {
+        #[command(subcommand)]
+        cmd: Option<CacheCmd>,
+    },
+    /// Serve a directory as a remote cache over HTTP
+    CacheServer {
+        /// Directory to store entries in
+        #[arg(long, default_value = "ghost-cache")]
+        dir: PathBuf,
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:9090")]
+        addr: String,
+        /// Reject uploads
+        #[arg(long)]
+        read_only: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCmd {
+    /// Show size, entry count and hit rate
+    Stats,
+    /// Evict least recently used entries above a size limit
+    Gc {
+        /// e.g. 500M, 5G (default: $GHOST_CACHE_MAX_SIZE, else 5G)
+        #[arg(long, value_name = "SIZE")]
+        max_size: Option<String>,
+    },
+    /// Remove every entry
+    Clear,
+}
+
+/// Options shared by build, test and run.
+#[derive(Debug, Args)]
+pub struct BuildArgs {
+    /// Build only these packages and their dependencies (repeatable)
+    #[arg(short, long = "package", value_name = "NAME")]
+    pub packages: Vec<String>,
+    /// native, ninja, or auto (ninja if it is on PATH)
+    #[arg(long, value_name = "EXECUTOR", default_value = "auto")]
+    pub executor: Executor,
+    /// Compile without the object cache
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Optimization level (0, 1, 2, 3, s, z, g)
+    #[arg(
+        short = 'O',
+        long,
+        value_name = "LEVEL",
+        help_heading = "Config overrides"
+    )]
+    pub opt_level: Option<String>,
+    /// Emit debug info (-g)
+    #[arg(short = 'g', long, help_heading = "Config overrides")]
+    pub debug_info: bool,
+    /// Omit debug info
+    #[arg(long, conflicts_with = "debug_info", help_heading = "Config overrides")]
+    pub no_debug_info: bool,
+    /// Sanitizers, e.g. address,undefined
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        help_heading = "Config overrides"
+    )]
+    pub sanitize: Vec<String>,
+    /// Full link-time optimization
+    #[arg(long, help_heading = "Config overrides")]
+    pub lto: bool,
+    /// ThinLTO
+    #[arg(long, conflicts_with = "lto", help_heading = "Config overrides")]
+    pub thin_lto: bool,
+    /// Disable LTO
+    #[arg(long, conflicts_with_all = ["lto", "thin_lto"], help_heading = "Config overrides")]
+    pub no_lto: bool,
+    /// Preprocessor define NAME[=VALUE] (repeatable)
+    #[arg(
+        short = 'D',
+        long = "define",
+        value_name = "DEF",
+        help_heading = "Config overrides"
+    )]
+    pub defines: Vec<String>,
+    /// Extra C compiler flag (repeatable)
+    #[arg(
+        long = "cflag",
+        value_name = "FLAG",
+        allow_hyphen_values = true,
+        help_heading = "Config overrides"
+    )]
+    pub cflags: Vec<String>,
+    /// Extra C++ compiler flag (repeatable)
+    #[arg(
+        long = "cxxflag",
+        value_name = "FLAG",
+        allow_hyphen_values = true,
+        help_heading = "Config overrides"
+    )]
+    pub cxxflags: Vec<String>,
+    /// Extra linker flag (repeatable)
+    #[arg(
+        long = "ldflag",
+        value_name = "FLAG",
+        allow_hyphen_values = true,
+        help_heading = "Config overrides"
+    )]
+    pub ldflags: Vec<String>,
+}
+
+impl BuildArgs {
+    /// The command line layer on top of the selected profile.
+    pub fn overrides(&self) -> ProfileFrag {
+        let list = |v: &Vec<String>| (!v.is_empty()).then(|| v.clone());
+        ProfileFrag {
+            opt_level: self.opt_level.clone(),
+            debug_info: match (self.debug_info, self.no_debug_info) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            sanitizers: list(&self.sanitize),
+            lto: if self.lto {
+                Some(Lto::Mode("full".into()))
+            } else if self.thin_lto {
+                Some(Lto::Mode("thin".into()))
+            } else if self.no_lto {
+                Some(Lto::Enabled(false))
+            } else {
+                None
+            },
+            defines: list(&self.defines),
+            cflags: list(&self.cflags),
+            cxxflags: list(&self.cxxflags),
+            ldflags: list(&self.ldflags),
+            ..Default::default()
+        }
+    }
+}
@@ -24,10 +24,19 @@ pub struct Toolchain {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Profile {
     pub name: String,
     pub defines: Vec<String>,
     pub exclude: Vec<String>,
+    pub opt_level: Option<String>,
+    pub debug_info: Option<bool>,
+    pub cflags: Vec<String>,
+    pub cxxflags: Vec<String>,
+    pub ldflags: Vec<String>,
+    pub sanitizers: Vec<String>,
+    /// `None` = off, otherwise `"full"` or `"thin"`.
+    pub lto: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -27,7 +27,7 @@ pub fn discover(
     for p in exclude {
         gb.add(Glob::new(p)?);
     }
-    for d in ["**/.git/**", "**/build/**", "**/.ghost/**"] {
+    for d in ["**/.git/**", "**/build/**", "**/.ghost/**", "**/.gen/**"] {
         gb.add(Glob::new(d)?);
     }
     let exc = gb.build()?;
@@ -0,0 +1,191 @@
+use std::{fmt, ops::Range};
+
+/// Errors about the workspace itself, as opposed to failures while building it.
+/// Each variant has its own exit code so scripts can tell them apart.
+#[derive(Debug, thiserror::Error)]
+pub enum GhostError {
+    #[error("{message}")]
+    Manifest { message: String, at: Location },
+    #[error(
+        "unsupported package.type \"{kind}\" (expected static, shared, interface, exe or test)"
+    )]
+    BadPackageType { kind: String, at: Location },
+    #[error("duplicate package name '{name}' in {first} and {second}")]
+    DuplicatePackage {
+        name: String,
+        first: String,
+        second: String,
+    },
+    #[error("package '{package}' depends on unknown package '{dep}' ({manifest})")]
+    UnknownDependency {
+        package: String,
+        dep: String,
+        manifest: String,
+    },
+    #[error("package '{package}' depends on '{dep}', which is {kind} package; only static, shared and interface packages can be dependencies ({manifest})", kind = article(.kind))]
+    BadDependency {
+        package: String,
+        dep: String,
+        kind: String,
+        manifest: String,
+    },
+    #[error("workspace member '{member}' has no ghost.build (listed in [workspace] members of ghost.build)")]
+    MissingManifest { member: String },
+    #[error("dependency cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("package '{package}' is missing {} source file(s):{}", .files.len(), list(.files))]
+    MissingSource { package: String, files: Vec<String> },
+    #[error("{origin}: {source}")]
+    InvalidGlob {
+        origin: String,
+        source: globset::Error,
+    },
+}
+
+impl GhostError {
+    /// Where in a manifest the problem is, if known; printed below the message.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            GhostError::Manifest { at, .. } | GhostError::BadPackageType { at, .. } => Some(at),
+            _ => None,
+        }
+    }
+
+    /// Stable identifier used by `ghost check --format json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GhostError::Manifest { .. } => "manifest",
+            GhostError::BadPackageType { .. } => "bad-package-type",
+            GhostError::DuplicatePackage { .. } => "duplicate-package",
+            GhostError::UnknownDependency { .. } => "unknown-dependency",
+            GhostError::Cycle(_) => "cycle",
+            GhostError::MissingSource { .. } => "missing-source",
+            GhostError::InvalidGlob { .. } => "invalid-glob",
+            GhostError::BadDependency { .. } => "bad-dependency",
+            GhostError::MissingManifest { .. } => "missing-manifest",
+        }
+    }
+
+    /// 1 is left for build and test failures and 2 for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GhostError::Manifest { .. } => 3,
+            GhostError::BadPackageType { .. } => 4,
+            GhostError::DuplicatePackage { .. } => 5,
+            GhostError::UnknownDependency { .. } => 6,
+            GhostError::Cycle(_) => 7,
+            GhostError::MissingSource { .. } => 8,
+            GhostError::InvalidGlob { .. } => 9,
+            GhostError::BadDependency { .. } => 10,
+            GhostError::MissingManifest { .. } => 11,
+        }
+    }
+}
+
+fn article(kind: &str) -> String {
+    match kind {
+        "exe" => "an exe".into(),
+        k => format!("a {k}"),
+    }
+}
+
+fn list(files: &[String]) -> String {
+    files.iter().map(|f| format!("\n  - {f}")).collect()
+}
+
+/// A position in a manifest, rendered as `--> path:line:col` followed by the
+/// offending line with the span underlined.
+#[derive(Debug)]
+pub struct Location {
+    pub path: String,
+    /// 1-based; 0 if only the file is known.
+    pub line: usize,
+    pub col: usize,
+    text: String,
+    width: usize,
+}
+
+impl Location {
+    /// `span` is a byte range into `src`, as reported by the TOML parser.
+    pub fn new(path: &str, src: &str, span: Option<Range<usize>>) -> Location {
+        let Some(span) = span else {
+            return Location {
+                path: path.to_string(),
+                line: 0,
+                col: 0,
+                text: String::new(),
+                width: 0,
+            };
+        };
+        let start = span.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let text = src[line_start..line_end].trim_end_matches('\r');
+        let end = span.end.clamp(start, line_end);
+        Location {
+            path: path.to_string(),
+            line: src[..start].matches('\n').count() + 1,
+            col: src[line_start..start].chars().count() + 1,
+            text: text.to_string(),
+            width: src[start..end].chars().count().max(1),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "  --> {}", self.path);
+        }
+        let num = self.line.to_string();
+        let pad = " ".repeat(num.len());
+        writeln!(f, "{pad}--> {}:{}:{}", self.path, self.line, self.col)?;
+        writeln!(f, "{pad} |")?;
+        writeln!(f, "{num} | {}", self.text)?;
+        write!(
+            f,
+            "{pad} | {}{}",
+            " ".repeat(self.col - 1),
+            "^".repeat(self.width)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn location_underlines_the_span() {
+        let src = "[package]\nname = \"add\"\ntype = \"stattic\"\n";
+        let start = src.find("\"stattic\"").unwrap();
+        let at = Location::new("libs/add/ghost.build", src, Some(start..start + 9));
+        assert_eq!((at.line, at.col), (3, 8));
+        assert_eq!(
+            at.to_string(),
+            " --> libs/add/ghost.build:3:8\n  |\n3 | type = \"stattic\"\n  |        ^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn location_stays_on_one_line() {
+        // a span running past the end of its line is cut there
+        let src = "a = 1\r\nb = [\n  2,\n]\n";
+        let at = Location::new("ghost.build", src, Some(11..src.len()));
+        assert_eq!(at.to_string().lines().nth(2), Some("2 | b = ["));
+        assert_eq!(at.to_string().lines().last(), Some("  |     ^"));
+        assert_eq!(
+            Location::new("ghost.build", src, None).to_string(),
+            "  --> ghost.build"
+        );
+    }
+
+    #[test]
+    fn parse_errors_point_into_the_manifest() {
+        let src = "[package]\nname = \"add\"\ntype = static\n";
+        let err = crate::manifest::parse::<toml::Table>("ghost.build", src).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        let at = err.location().unwrap();
+        assert_eq!((at.line, at.col), (3, 8));
+    }
+}
@@ -0,0 +1,417 @@
+use crate::ninja::{Edge, NinjaBuf};
+use crate::ui;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env, fs,
+    path::Path,
+    process::{Command, Output},
+    sync::mpsc,
+    thread,
+    time::SystemTime,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Executor {
+    /// Ninja if it is on `PATH`, the native executor otherwise.
+    Auto,
+    Ninja,
+    Native,
+}
+
+impl std::str::FromStr for Executor {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "ninja" => Ok(Self::Ninja),
+            "native" => Ok(Self::Native),
+            other => bail!("--executor: expected native, ninja or auto, got '{other}'"),
+        }
+    }
+}
+
+pub fn ninja_on_path() -> bool {
+    let exe = if cfg!(windows) { "ninja.exe" } else { "ninja" };
+    env::var_os("PATH")
+        .map(|p| env::split_paths(&p).any(|d| d.join(exe).is_file()))
+        .unwrap_or(false)
+}
+
+const LOG_NAME: &str = ".ghost_log";
+
+/// What the last successful run of an edge looked like, keyed by its first output.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LogEntry {
+    /// sha256 of the command line.
+    hash: String,
+    /// Headers from the depfile of the last compile.
+    #[serde(default)]
+    deps: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BuildLog {
+    version: u32,
+    entries: HashMap<String, LogEntry>,
+}
+
+impl BuildLog {
+    fn load(path: &Path) -> BuildLog {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<BuildLog>(&s).ok())
+            .filter(|l| l.version == 1)
+            .unwrap_or_default()
+    }
+
+    fn save(&mut self, path: &Path, edges: &[Edge]) -> Result<()> {
+        // drop outputs that are no longer part of the graph
+        let live: HashSet<&String> = edges.iter().filter_map(|e| e.outputs.first()).collect();
+        self.entries.retain(|k, _| live.contains(k));
+        self.version = 1;
+        fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("write {}", path.display()))
+    }
+}
+
+fn command_hash(cmd: &str) -> String {
+    hex::encode(Sha256::digest(cmd.as_bytes()))
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reads the prerequisites from a Makefile-style depfile as written by `-MMD -MF`.
+pub fn parse_depfile(text: &str) -> Vec<String> {
+    let text = text.replace("\\\r\n", " ").replace("\\\n", " ");
+    // the target ends at the first ':' followed by whitespace (not a drive letter)
+    let body = text
+        .char_indices()
+        .find(|&(i, c)| c == ':' && text[i + 1..].starts_with(char::is_whitespace))
+        .map(|(i, _)| &text[i + 1..])
+        .unwrap_or("");
+    let mut deps = vec![];
+    let mut cur = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                cur.push(' ');
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                cur.push('$');
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                if !cur.is_empty() {
+                    deps.push(std::mem::take(&mut cur));
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        deps.push(cur);
+    }
+    deps
+}
+
+struct Job {
+    command: String,
+    hash: String,
+    depfile: Option<String>,
+}
+
+/// Runs the edges of `nin` with up to `jobs` commands at a time, rebuilding only
+/// what is out of date. An edge is dirty when an output is missing, its command
+/// changed since the last run (per the build log in `build_dir`), or an input,
+/// implicit input or header from its depfile is newer than its oldest output.
+/// With `targets`, only the edges those outputs need are considered.
+pub fn run(nin: &NinjaBuf, build_dir: &str, jobs: usize, targets: &[String]) -> Result<()> {
+    let edges = nin.edges();
+    let log_path = Path::new(build_dir).join(LOG_NAME);
+    let mut log = BuildLog::load(&log_path);
+
+    let mut producer: HashMap<&str, usize> = HashMap::new();
+    for (i, e) in edges.iter().enumerate() {
+        for o in &e.outputs {
+            producer.insert(o.as_str(), i);
+        }
+    }
+    let prereqs: Vec<Vec<usize>> = edges
+        .iter()
+        .map(|e| {
+            let mut p: Vec<usize> = e
+                .inputs
+                .iter()
+                .chain(&e.implicit)
+                .chain(&e.order_only)
+                .filter_map(|f| producer.get(f.as_str()).copied())
+                .collect();
+            p.sort_unstable();
+            p.dedup();
+            p
+        })
+        .collect();
+
+    let mut order = topo_order(edges, &prereqs)?;
+    if !targets.is_empty() {
+        let mut wanted = vec![false; edges.len()];
+        let mut todo = vec![];
+        for t in targets {
+            match producer.get(t.as_str()) {
+                Some(&i) => todo.push(i),
+                None => bail!("unknown target '{t}'"),
+            }
+        }
+        while let Some(i) = todo.pop() {
+            if !std::mem::replace(&mut wanted[i], true) {
+                todo.extend(&prereqs[i]);
+            }
+        }
+        order.retain(|&i| wanted[i]);
+    }
+    let jobs_for: Vec<Job> = edges
+        .iter()
+        .map(|e| {
+            let command = nin.binding(e, "command").unwrap_or_default();
+            Job {
+                hash: command_hash(&command),
+                command,
+                depfile: nin.binding(e, "depfile"),
+            }
+        })
+        .collect();
+
+    // an edge is dirty if it is stale itself or an input will be rebuilt; order-only
+    // inputs only sequence it, but headers from its last depfile count as inputs
+    let mut dirty = vec![false; edges.len()];
+    for &i in &order {
+        let e = &edges[i];
+        let entry = e.outputs.first().and_then(|o| log.entries.get(o));
+        dirty[i] = e
+            .inputs
+            .iter()
+            .chain(&e.implicit)
+            .chain(entry.into_iter().flat_map(|en| &en.deps))
+            .filter_map(|f| producer.get(f.as_str()))
+            .any(|&p| dirty[p])
+            || is_stale(e, &jobs_for[i], entry, &producer)?;
+    }
+
+    let total = dirty.iter().filter(|&&d| d).count();
+    if total == 0 {
+        ui::status!("ghost: no work to do.");
+        return Ok(());
+    }
+
+    let mut waiting: Vec<usize> = prereqs
+        .iter()
+        .map(|p| p.iter().filter(|&&d| dirty[d]).count())
+        .collect();
+    let mut dependents: Vec<Vec<usize>> = vec![vec![]; edges.len()];
+    for (i, p) in prereqs.iter().enumerate() {
+        for &d in p {
+            dependents[d].push(i);
+        }
+    }
+    let mut ready: VecDeque<usize> = order
+        .iter()
+        .copied()
+        .filter(|&i| dirty[i] && waiting[i] == 0)
+        .collect();
+
+    let (tx, rx) = mpsc::channel::<(usize, std::io::Result<Output>)>();
+    let mut running = 0usize;
+    let mut finished = 0usize;
+    let mut failed: Option<String> = None;
+    let jobs = jobs.max(1);
+
+    loop {
+        while failed.is_none() && running < jobs {
+            let Some(i) = ready.pop_front() else { break };
+            for o in &edges[i].outputs {
+                if let Some(dir) = Path::new(o).parent() {
+                    fs::create_dir_all(dir).ok();
+                }
+            }
+            let tx = tx.clone();
+            let command = jobs_for[i].command.clone();
+            thread::spawn(move || {
+                let _ = tx.send((i, shell(&command).output()));
+            });
+            running += 1;
+        }
+        if running == 0 {
+            break;
+        }
+        let (i, res) = rx.recv().map_err(|_| anyhow!("executor channel closed"))?;
+        running -= 1;
+        finished += 1;
+        let e = &edges[i];
+        let job = &jobs_for[i];
+        let first = e.outputs.first().cloned().unwrap_or_default();
+        ui::status!("[{}/{}] {} {}", finished, total, e.rule, first);
+        if ui::level() == ui::Level::Verbose {
+            eprintln!("{}", job.command);
+        }
+
+        let output = match res {
+            Ok(o) => o,
+            Err(err) => {
+                eprintln!("FAILED: {}\n{}\n{}", first, job.command, err);
+                failed.get_or_insert(first);
+                continue;
+            }
+        };
+        let mut captured = String::from_utf8_lossy(&output.stdout).to_string();
+        captured.push_str(&String::from_utf8_lossy(&output.stderr));
+        if !output.status.success() {
+            eprintln!("FAILED: {}\n{}", first, job.command);
+            eprint!("{}", captured);
+            failed.get_or_insert(first);
+            continue;
+        }
+        eprint!("{}", captured);
+
+        let deps = job
+            .depfile
+            .as_deref()
+            .and_then(|d| fs::read_to_string(d).ok())
+            .map(|t| parse_depfile(&t))
+            .unwrap_or_default();
+        log.entries.insert(
+            first,
+            LogEntry {
+                hash: job.hash.clone(),
+                deps,
+            },
+        );
+        for &d in &dependents[i] {
+            if dirty[d] {
+                waiting[d] -= 1;
+                if waiting[d] == 0 {
+                    ready.push_back(d);
+                }
+            }
+        }
+    }
+
+    log.save(&log_path, edges)?;
+    if let Some(out) = failed {
+        bail!("build stopped: subcommand failed ({out})");
+    }
+    Ok(())
+}
+
+fn is_stale(
+    e: &Edge,
+    job: &Job,
+    entry: Option<&LogEntry>,
+    producer: &HashMap<&str, usize>,
+) -> Result<bool> {
+    let Some(entry) = entry else { return Ok(true) };
+    if entry.hash != job.hash {
+        return Ok(true);
+    }
+    let mut oldest_out: Option<SystemTime> = None;
+    for o in &e.outputs {
+        match mtime(o) {
+            Some(t) => oldest_out = Some(oldest_out.map_or(t, |m| m.min(t))),
+            None => return Ok(true),
+        }
+    }
+    let Some(oldest_out) = oldest_out else {
+        return Ok(true);
+    };
+    for f in e.inputs.iter().chain(&e.implicit) {
+        match mtime(f) {
+            Some(t) if t > oldest_out => return Ok(true),
+            Some(_) => {}
+            None if producer.contains_key(f.as_str()) => return Ok(true),
+            None => bail!(
+                "'{}', needed by '{}', missing and no known rule to make it",
+                f,
+                e.outputs.first().map(String::as_str).unwrap_or("?")
+            ),
+        }
+    }
+    // a header that disappeared or changed means the object must be rebuilt
+    Ok(entry
+        .deps
+        .iter()
+        .any(|d| mtime(d).is_none_or(|t| t > oldest_out)))
+}
+
+fn topo_order(edges: &[Edge], prereqs: &[Vec<usize>]) -> Result<Vec<usize>> {
+    let mut state = vec![0u8; edges.len()];
+    let mut order = Vec::with_capacity(edges.len());
+    for start in 0..edges.len() {
+        if state[start] != 0 {
+            continue;
+        }
+        let mut stack = vec![(start, 0usize)];
+        state[start] = 1;
+        while let Some(&mut (i, ref mut next)) = stack.last_mut() {
+            if let Some(&p) = prereqs[i].get(*next) {
+                *next += 1;
+                match state[p] {
+                    0 => {
+                        state[p] = 1;
+                        stack.push((p, 0));
+                    }
+                    1 => bail!(
+                        "dependency cycle involving '{}'",
+                        edges[p].outputs.first().map(String::as_str).unwrap_or("?")
+                    ),
+                    _ => {}
+                }
+            } else {
+                state[i] = 2;
+                order.push(i);
+                stack.pop();
+            }
+        }
+    }
+    Ok(order)
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.args(["/C", command]);
+        c
+    } else {
+        let mut c = Command::new("/bin/sh");
+        c.args(["-c", command]);
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depfile_with_continuations() {
+        let d = "obj/a.o: src/a.c \\\n  include/a.h \\\r\n  include/b.h\n";
+        assert_eq!(parse_depfile(d), ["src/a.c", "include/a.h", "include/b.h"]);
+    }
+
+    #[test]
+    fn depfile_escapes() {
+        let d = "obj/a.o: my\\ dir/a.c cost$$.h C:\\inc\\x.h\n";
+        assert_eq!(parse_depfile(d), ["my dir/a.c", "cost$.h", "C:\\inc\\x.h"]);
+    }
+
+    #[test]
+    fn depfile_target_with_drive_letter() {
+        assert_eq!(parse_depfile("C:/obj/a.o: C:/src/a.c"), ["C:/src/a.c"]);
+        assert!(parse_depfile("").is_empty());
+    }
+}
@@ -0,0 +1,406 @@
+use crate::error::GhostError;
+use crate::manifest::{load_package_manifest, PackageManifest};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct PkgNode {
+    pub name: String,
+    pub root: PathBuf,
+    pub manifest: PackageManifest,
+}
+
+impl PkgNode {
+    /// `ghost.build` of the package, relative to the current directory if possible.
+    pub fn manifest_path(&self) -> String {
+        let path = self.root.join("ghost.build");
+        std::env::current_dir()
+            .ok()
+            .and_then(|cwd| pathdiff::diff_paths(&path, cwd))
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    pub fn kind(&self) -> &str {
+        self.manifest.package.r#type.as_str()
+    }
+
+    pub fn direct(&self) -> &[String] {
+        self.manifest
+            .deps
+            .as_ref()
+            .and_then(|d| d.direct.as_deref())
+            .unwrap_or_default()
+    }
+
+    pub fn private(&self) -> &[String] {
+        self.manifest
+            .deps
+            .as_ref()
+            .and_then(|d| d.private.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct Graph {
+    pub nodes: Vec<PkgNode>,
+    /// `deps.direct` followed by `deps.private`.
+    deps: Vec<Vec<usize>>,
+    /// Only `deps.direct`; these propagate usage requirements to dependents.
+    public: Vec<Vec<usize>>,
+    order: Vec<usize>,
+}
+
+impl Graph {
+    pub fn new(nodes: Vec<PkgNode>) -> Result<Graph> {
+        if let Some(e) = validate(&nodes).into_iter().next() {
+            return Err(e.into());
+        }
+        let index: HashMap<&str, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.name.as_str(), i))
+            .collect();
+        let resolve =
+            |names: &[String]| -> Vec<usize> { names.iter().map(|d| index[d.as_str()]).collect() };
+        let mut deps = Vec::with_capacity(nodes.len());
+        let mut public = Vec::with_capacity(nodes.len());
+        for n in &nodes {
+            let direct = resolve(n.direct());
+            let mut all = direct.clone();
+            all.extend(resolve(n.private()));
+            deps.push(all);
+            public.push(direct);
+        }
+
+        let mut g = Graph {
+            nodes,
+            deps,
+            public,
+            order: vec![],
+        };
+        g.order = g.topo_sort()?;
+        Ok(g)
+    }
+
+    /// Dependencies before dependents.
+    pub fn build_order(&self) -> &[usize] {
+        &self.order
+    }
+
+    fn topo_sort(&self) -> Result<Vec<usize>, GhostError> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            New,
+            Active,
+            Done,
+        }
+        let mut marks = vec![Mark::New; self.nodes.len()];
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack: Vec<usize> = vec![];
+
+        fn visit(
+            g: &Graph,
+            i: usize,
+            marks: &mut [Mark],
+            stack: &mut Vec<usize>,
+            order: &mut Vec<usize>,
+        ) -> Result<(), GhostError> {
+            match marks[i] {
+                Mark::Done => return Ok(()),
+                Mark::Active => {
+                    let start = stack.iter().position(|&s| s == i).unwrap_or(0);
+                    let mut cycle: Vec<String> = stack[start..]
+                        .iter()
+                        .map(|&s| g.nodes[s].name.clone())
+                        .collect();
+                    cycle.push(g.nodes[i].name.clone());
+                    return Err(GhostError::Cycle(cycle));
+                }
+                Mark::New => {}
+            }
+            marks[i] = Mark::Active;
+            stack.push(i);
+            for &d in &g.deps[i] {
+                visit(g, d, marks, stack, order)?;
+            }
+            stack.pop();
+            marks[i] = Mark::Done;
+            order.push(i);
+            Ok(())
+        }
+
+        for i in 0..self.nodes.len() {
+            visit(self, i, &mut marks, &mut stack, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn sorted_dependents_first(&self, set: &[bool]) -> Vec<usize> {
+        self.order
+            .iter()
+            .rev()
+            .copied()
+            .filter(|&i| set[i])
+            .collect()
+    }
+
+    /// Packages whose usage requirements (include dirs, defines) apply to `idx`:
+    /// its direct and private deps and, transitively, their public deps.
+    pub fn usage_closure(&self, idx: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut todo: Vec<usize> = self.deps[idx].clone();
+        while let Some(i) = todo.pop() {
+            if !std::mem::replace(&mut seen[i], true) {
+                todo.extend(&self.public[i]);
+            }
+        }
+        seen[idx] = false;
+        self.sorted_dependents_first(&seen)
+    }
+
+    /// All transitive dependencies of `idx` (public and private), dependents first.
+    pub fn closure(&self, idx: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut todo: Vec<usize> = self.deps[idx].clone();
+        while let Some(i) = todo.pop() {
+            if !std::mem::replace(&mut seen[i], true) {
+                todo.extend(&self.deps[i]);
+            }
+        }
+        seen[idx] = false;
+        self.sorted_dependents_first(&seen)
+    }
+
+    /// Libraries to put on the link line of `idx`, dependents before dependencies.
+    /// Static libraries reachable only through a shared library are already
+    /// linked into it and are not repeated.
+    pub fn link_closure(&self, idx: usize) -> Vec<usize> {
+        let reach = self.link_reach(idx);
+        let linked: Vec<bool> = (0..self.nodes.len())
+            .map(|i| match self.nodes[i].kind() {
+                "shared" => reach[i] != [false; 2],
+                "static" => reach[i][0],
+                _ => false,
+            })
+            .collect();
+        self.sorted_dependents_first(&linked)
+    }
+
+    /// Packages whose `link_libs`/`link_dirs` go on the link line of `idx`,
+    /// dependents first, with whether their [private] section counts too. As in
+    /// `link_closure`, what is behind a shared library is already linked into it:
+    /// only the shared libraries there are passed on, with their public section.
+    pub fn link_flag_closure(&self, idx: usize) -> Vec<(usize, bool)> {
+        let reach = self.link_reach(idx);
+        let shared = |i: usize| self.nodes[i].kind() == "shared";
+        let used: Vec<bool> = (0..self.nodes.len())
+            .map(|i| reach[i][0] || (reach[i][1] && shared(i)))
+            .collect();
+        self.sorted_dependents_first(&used)
+            .into_iter()
+            .map(|i| (i, reach[i][0] && !shared(i)))
+            .collect()
+    }
+
+    // For each package: reached from `idx` without passing a shared library, and
+    // reached behind one.
+    fn link_reach(&self, idx: usize) -> Vec<[bool; 2]> {
+        let mut seen = vec![[false; 2]; self.nodes.len()];
+        let mut todo: Vec<(usize, bool)> = self.deps[idx].iter().map(|&d| (d, false)).collect();
+        while let Some((i, behind_shared)) = todo.pop() {
+            if std::mem::replace(&mut seen[i][behind_shared as usize], true) {
+                continue;
+            }
+            let next = behind_shared || self.nodes[i].kind() == "shared";
+            todo.extend(self.deps[i].iter().map(|&d| (d, next)));
+        }
+        seen[idx] = [false; 2];
+        seen
+    }
+
+    /// Static libraries that end up inside a shared library and therefore need `-fPIC`.
+    pub fn needs_pic(&self) -> Vec<bool> {
+        let mut pic = vec![false; self.nodes.len()];
+        for (i, n) in self.nodes.iter().enumerate() {
+            if n.kind() == "shared" {
+                pic[i] = true;
+                for d in self.closure(i) {
+                    if self.nodes[d].kind() == "static" {
+                        pic[d] = true;
+                    }
+                }
+            }
+        }
+        pic
+    }
+}
+
+/// Workspace-level checks, run before anything is generated: unique package names,
+/// and `deps` that name an existing library package. Returns every problem found.
+pub fn validate(nodes: &[PkgNode]) -> Vec<GhostError> {
+    let mut errors = vec![];
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, n) in nodes.iter().enumerate() {
+        match index.get(n.name.as_str()) {
+            Some(&first) => errors.push(GhostError::DuplicatePackage {
+                name: n.name.clone(),
+                first: nodes[first].manifest_path(),
+                second: n.manifest_path(),
+            }),
+            None => {
+                index.insert(&n.name, i);
+            }
+        }
+    }
+    for n in nodes {
+        for d in n.direct().iter().chain(n.private()) {
+            match index.get(d.as_str()).map(|&i| nodes[i].kind()) {
+                None => errors.push(GhostError::UnknownDependency {
+                    package: n.name.clone(),
+                    dep: d.clone(),
+                    manifest: n.manifest_path(),
+                }),
+                Some(kind @ ("exe" | "test")) => errors.push(GhostError::BadDependency {
+                    package: n.name.clone(),
+                    dep: d.clone(),
+                    kind: kind.to_string(),
+                    manifest: n.manifest_path(),
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+    errors
+}
+
+/// Reads the `ghost.build` of every workspace member.
+pub fn load_members(members: &[String]) -> Result<Vec<PkgNode>> {
+    let mut nodes = Vec::with_capacity(members.len());
+    for m in members {
+        let path = Path::new(m).join("ghost.build");
+        if !path.is_file() {
+            return Err(GhostError::MissingManifest { member: m.clone() }.into());
+        }
+        let root = PathBuf::from(m)
+            .canonicalize()
+            .with_context(|| format!("workspace member '{m}'"))?;
+        let manifest = load_package_manifest(&path.to_string_lossy())?;
+        nodes.push(PkgNode {
+            name: manifest.package.name.clone(),
+            root,
+            manifest,
+        });
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, kind: &str, direct: &[&str], private: &[&str]) -> PkgNode {
+        let manifest = toml::from_str(&format!(
+            "[package]\nname = \"{name}\"\ntype = \"{kind}\"\n\
+             [sources]\nfiles = [\"{name}.c\"]\n\
+             [deps]\ndirect = {direct:?}\nprivate = {private:?}\n"
+        ))
+        .unwrap();
+        PkgNode {
+            name: name.into(),
+            root: PathBuf::from(name),
+            manifest,
+        }
+    }
+
+    fn names(g: &Graph, idx: &[usize]) -> Vec<String> {
+        idx.iter().map(|&i| g.nodes[i].name.clone()).collect()
+    }
+
+    fn find(g: &Graph, name: &str) -> usize {
+        g.nodes.iter().position(|n| n.name == name).unwrap()
+    }
+
+    #[test]
+    fn cycle_is_named() {
+        let err = Graph::new(vec![
+            node("a", "static", &["b"], &[]),
+            node("b", "static", &[], &["c"]),
+            node("c", "static", &["a"], &[]),
+        ])
+        .unwrap_err();
+        assert_eq!(err.to_string(), "dependency cycle: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn dependencies_build_first_and_link_last() {
+        let g = Graph::new(vec![
+            node("app", "exe", &["io", "add"], &[]),
+            node("add", "static", &[], &[]),
+            node("io", "static", &["add"], &[]),
+        ])
+        .unwrap();
+        assert_eq!(names(&g, g.build_order()), ["add", "io", "app"]);
+        assert_eq!(names(&g, &g.link_closure(find(&g, "app"))), ["io", "add"]);
+    }
+
+    #[test]
+    fn statics_behind_a_shared_lib_are_not_repeated() {
+        let g = Graph::new(vec![
+            node("app", "exe", &["gui", "add"], &[]),
+            node("gui", "shared", &["util"], &[]),
+            node("util", "static", &[], &[]),
+            node("add", "static", &[], &[]),
+        ])
+        .unwrap();
+        // gui and add are unrelated, so their relative order is not fixed
+        let mut app = names(&g, &g.link_closure(find(&g, "app")));
+        app.sort();
+        assert_eq!(app, ["add", "gui"]);
+        assert_eq!(names(&g, &g.link_closure(find(&g, "gui"))), ["util"]);
+        let pic = g.needs_pic();
+        assert!(pic[find(&g, "util")]);
+        assert!(!pic[find(&g, "add")]);
+    }
+
+    #[test]
+    fn link_flags_stop_at_a_shared_lib() {
+        let g = Graph::new(vec![
+            node("app", "exe", &["gui", "core"], &[]),
+            node("gui", "shared", &[], &["png"]),
+            node("png", "static", &["core"], &[]),
+            node("core", "interface", &[], &[]),
+        ])
+        .unwrap();
+        // png's [private] link_libs went into gui; core is also a direct dep of app
+        let mut app = g.link_flag_closure(find(&g, "app"));
+        app.sort();
+        assert_eq!(app, [(find(&g, "gui"), false), (find(&g, "core"), true)]);
+        assert_eq!(
+            g.link_flag_closure(find(&g, "gui")),
+            [(find(&g, "png"), true), (find(&g, "core"), true)]
+        );
+    }
+
+    #[test]
+    fn private_deps_do_not_reach_dependents() {
+        let g = Graph::new(vec![
+            node("app", "exe", &["lib"], &[]),
+            node("lib", "static", &["core"], &["zlib"]),
+            node("core", "interface", &[], &[]),
+            node("zlib", "static", &[], &[]),
+        ])
+        .unwrap();
+        assert_eq!(
+            names(&g, &g.usage_closure(find(&g, "app"))),
+            ["lib", "core"]
+        );
+        // still linked, since lib needs it
+        assert_eq!(names(&g, &g.link_closure(find(&g, "app"))), ["lib", "zlib"]);
+    }
+}
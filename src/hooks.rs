@@ -1,7 +1,22 @@
 use crate::context::Ctx;
-use anyhow::Result;
-use mlua::{Function, Lua, LuaSerdeExt, Value};
-use std::fs;
+use crate::graph::{Graph, PkgNode};
+use crate::manifest::{assert_package, PackageManifest};
+use anyhow::{anyhow, bail, Context, Result};
+use mlua::{
+    Function, Lua, LuaOptions, LuaSerdeExt, MultiValue, SerializeOptions, StdLib, Table, Value,
+};
+use serde::{de::DeserializeOwned, de::IgnoredAny, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Capabilities a project can grant its hooks with `[hooks] allow = [...]`.
+pub const CAPABILITIES: &[&str] = &["exec", "fs.read", "fs.write"];
+
+// `os` functions hooks keep; the rest of the library is replaced by stubs.
+const SAFE_OS: &[&str] = &["clock", "date", "difftime", "getenv", "time"];
 
 fn exec_shell(cmdline: &str, cwd: &str) -> (i32, String, String) {
     #[cfg(target_os = "windows")]
@@ -24,53 +39,778 @@ fn exec_shell(cmdline: &str, cwd: &str) -> (i32, String, String) {
     }
 }
 
-pub fn run_lua_hooks(ctx: Ctx, project_root: &str) -> Result<Ctx> {
-    let path = format!("{project_root}/build.lua");
-    if !std::path::Path::new(&path).exists() {
-        return Ok(ctx);
+fn runtime_error(msg: String) -> mlua::Error {
+    mlua::Error::RuntimeError(msg)
+}
+
+// A function that always fails, naming what it would need; the caller adds the hook.
+fn deny<'lua>(lua: &'lua Lua, what: &str, why: String) -> mlua::Result<Function<'lua>> {
+    let what = what.to_string();
+    lua.create_function(move |_, _: MultiValue| -> mlua::Result<()> {
+        Err(runtime_error(format!("{what} {why}")))
+    })
+}
+
+fn needs(cap: &str) -> String {
+    format!("needs the '{cap}' capability (add it to [hooks] allow in ghost.build)")
+}
+
+// Resolves `path` against `base` (the directory of the build.lua) and refuses
+// anything outside the workspace root.
+fn confine(
+    root: &Path,
+    base: &Path,
+    path: &str,
+    write: bool,
+) -> std::result::Result<PathBuf, String> {
+    let full = base.join(path);
+    let existing = if write {
+        full.parent().map(Path::to_path_buf).unwrap_or_default()
+    } else {
+        full.clone()
+    };
+    let canon = existing
+        .canonicalize()
+        .map_err(|e| format!("'{path}': {e}"))?;
+    if !canon.starts_with(root) {
+        return Err(format!("'{path}' is outside the workspace"));
+    }
+    // writing follows a symlink at the target itself, so check where it points
+    if write && full.symlink_metadata().is_ok() {
+        let target = full
+            .canonicalize()
+            .map_err(|_| format!("'{path}' is a symlink to a missing file"))?;
+        if !target.starts_with(root) {
+            return Err(format!("'{path}' is outside the workspace"));
+        }
     }
+    Ok(full)
+}
+
+fn fs_table<'lua>(
+    lua: &'lua Lua,
+    root: &Path,
+    base: &Path,
+    allow: &[String],
+) -> mlua::Result<Table<'lua>> {
+    let t = lua.create_table()?;
+    if allow.iter().any(|a| a == "fs.read") {
+        let (r, b) = (root.to_path_buf(), base.to_path_buf());
+        t.set(
+            "read",
+            lua.create_function(move |_, path: String| {
+                let p = confine(&r, &b, &path, false)
+                    .map_err(|e| runtime_error(format!("fs.read {e}")))?;
+                fs::read_to_string(&p).map_err(|e| runtime_error(format!("fs.read '{path}': {e}")))
+            })?,
+        )?;
+        let (r, b) = (root.to_path_buf(), base.to_path_buf());
+        t.set(
+            "exists",
+            lua.create_function(move |_, path: String| {
+                Ok(confine(&r, &b, &path, false).is_ok_and(|p| p.exists()))
+            })?,
+        )?;
+    } else {
+        t.set("read", deny(lua, "fs.read", needs("fs.read"))?)?;
+        t.set("exists", deny(lua, "fs.exists", needs("fs.read"))?)?;
+    }
+    if allow.iter().any(|a| a == "fs.write") {
+        let (r, b) = (root.to_path_buf(), base.to_path_buf());
+        t.set(
+            "write",
+            lua.create_function(move |_, (path, data): (String, mlua::String)| {
+                let p = confine(&r, &b, &path, true)
+                    .map_err(|e| runtime_error(format!("fs.write {e}")))?;
+                fs::write(&p, data.as_bytes())
+                    .map_err(|e| runtime_error(format!("fs.write '{path}': {e}")))
+            })?,
+        )?;
+    } else {
+        t.set("write", deny(lua, "fs.write", needs("fs.write"))?)?;
+    }
+    Ok(t)
+}
 
-    let lua_src = fs::read_to_string(&path)?;
-    let lua = Lua::new();
+// A Lua state with only the safe parts of the standard library, plus `exec` and
+// `fs` as granted by `allow`. Everything else that touches the system is a stub
+// that raises an error naming the hook and the missing capability. Relative paths
+// and `exec` start from `base`.
+fn sandbox(root: &Path, base: &Path, allow: &[String]) -> Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8 | StdLib::OS,
+        LuaOptions::default(),
+    )?;
+    install(&lua, root, base, allow)?;
+    Ok(lua)
+}
+
+fn install(lua: &Lua, root: &Path, base: &Path, allow: &[String]) -> mlua::Result<()> {
     let globals = lua.globals();
 
-    let ctx_val = lua.to_value(&ctx)?;
-    globals.set("ctx", ctx_val)?;
-
-    let pr = project_root.to_string();
-    let exec_fn = lua.create_function(move |lua_ctx, cmd: String| {
-        let (code, out, err) = exec_shell(&cmd, &pr);
-        let t = lua_ctx.create_table()?;
-        t.set("code", code)?;
-        t.set("stdout", out)?;
-        t.set("stderr", err)?;
-        Ok(t)
-    })?;
-    globals.set("exec", exec_fn)?;
-
-    lua.load(&lua_src).set_name("build.lua").exec()?;
-
-    let call = |name: &str| -> mlua::Result<()> {
-        match globals.get::<_, Value>(name)? {
-            Value::Function(f) => {
-                let c: Value = globals.get("ctx")?;
-                let f: Function = f;
-                f.call::<_, ()>(c)?;
+    for f in ["dofile", "loadfile"] {
+        globals.set(f, deny(lua, f, needs("fs.read") + "; use fs.read")?)?;
+    }
+    globals.set(
+        "require",
+        deny(lua, "require", "is not available in hooks".into())?,
+    )?;
+
+    let os: Table = globals.get("os")?;
+    let names: Vec<String> = os
+        .clone()
+        .pairs::<String, Value>()
+        .filter_map(|p| p.ok().map(|(k, _)| k))
+        .collect();
+    for name in names {
+        if SAFE_OS.contains(&name.as_str()) {
+            continue;
+        }
+        let why = match name.as_str() {
+            "execute" => needs("exec") + "; use exec",
+            "remove" | "rename" | "tmpname" => "is not available in hooks; use fs.write".into(),
+            _ => "is not available in hooks".into(),
+        };
+        os.set(name.as_str(), deny(lua, &format!("os.{name}"), why)?)?;
+    }
+
+    // `io` is a table whose every field raises on access
+    let io = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set(
+        "__index",
+        lua.create_function(move |_, (_, key): (Value, String)| -> mlua::Result<()> {
+            Err(runtime_error(format!(
+                "io.{key} is not available in hooks; use fs.read / fs.write \
+                 (capabilities 'fs.read' / 'fs.write')"
+            )))
+        })?,
+    )?;
+    io.set_metatable(Some(meta));
+    globals.set("io", io)?;
+
+    if allow.iter().any(|a| a == "exec") {
+        let pr = base.display().to_string();
+        let exec_fn = lua.create_function(move |lua_ctx, cmd: String| {
+            let (code, out, err) = exec_shell(&cmd, &pr);
+            let t = lua_ctx.create_table()?;
+            t.set("code", code)?;
+            t.set("stdout", out)?;
+            t.set("stderr", err)?;
+            Ok(t)
+        })?;
+        globals.set("exec", exec_fn)?;
+    } else {
+        globals.set("exec", deny(lua, "exec", needs("exec"))?)?;
+    }
+    globals.set("fs", fs_table(lua, root, base, allow)?)?;
+    Ok(())
+}
+
+/// A package as hooks see it: its manifest, laid out as in `ghost.build`, plus the
+/// directory it lives in. Optional tables and lists are filled in with empty ones
+/// so hooks can append to them directly.
+#[derive(Debug, Serialize)]
+struct LuaPackage {
+    root: String,
+    #[serde(flatten)]
+    manifest: PackageManifest,
+}
+
+impl LuaPackage {
+    fn new(node: &PkgNode) -> LuaPackage {
+        let mut m = node.manifest.clone();
+        for pp in [&mut m.public, &mut m.private] {
+            let pp = pp.get_or_insert_with(Default::default);
+            for v in [
+                &mut pp.include_dirs,
+                &mut pp.defines,
+                &mut pp.link_libs,
+                &mut pp.link_dirs,
+            ] {
+                v.get_or_insert_with(Vec::new);
             }
-            _ => {}
+        }
+        let deps = m.deps.get_or_insert_with(Default::default);
+        deps.direct.get_or_insert_with(Vec::new);
+        deps.private.get_or_insert_with(Vec::new);
+        LuaPackage {
+            root: node.root.display().to_string(),
+            manifest: m,
+        }
+    }
+}
+
+fn lua_packages(nodes: &[PkgNode]) -> BTreeMap<String, LuaPackage> {
+    nodes
+        .iter()
+        .map(|n| (n.name.clone(), LuaPackage::new(n)))
+        .collect()
+}
+
+#[derive(Serialize)]
+struct GraphView<'a> {
+    packages: BTreeMap<String, LuaPackage>,
+    /// Package names, dependencies first.
+    order: Vec<&'a str>,
+}
+
+// Reads back a package a hook edited or added. `name` is the key it is stored under,
+// so new packages can leave out `package.name`. Read straight from the table (not
+// through `flatten`) so that an emptied list `{}` is still a list.
+fn lua_package<'lua>(lua: &'lua Lua, name: &str, v: Value<'lua>) -> mlua::Result<LuaPackage> {
+    let read = || -> mlua::Result<LuaPackage> {
+        let Value::Table(t) = &v else {
+            return Err(runtime_error("expected a table".into()));
         };
-        Ok(())
+        if let Value::Table(p) = t.get::<_, Value>("package")? {
+            p.set("name", name)?;
+        }
+        Ok(LuaPackage {
+            root: t
+                .get::<_, Option<String>>("root")?
+                .ok_or_else(|| runtime_error("`root` is missing".into()))?,
+            manifest: lua.from_value(v.clone())?,
+        })
     };
+    read().map_err(|e| runtime_error(format!("package '{name}': {e}")))
+}
 
-    for h in [
-        "before_discover",
-        "before_generate",
-        "before_build",
-        "after_build",
-    ] {
-        let _ = call(h);
+// The packages of the graph `before_generate` returned or edited, by name.
+fn lua_graph<'lua>(lua: &'lua Lua, v: Value<'lua>) -> mlua::Result<BTreeMap<String, LuaPackage>> {
+    let Value::Table(graph) = v else {
+        return Err(runtime_error("graph must be a table".into()));
+    };
+    let packages: Table = graph.get("packages")?;
+    packages
+        .pairs::<String, Value>()
+        .map(|p| {
+            let (name, v) = p?;
+            let pkg = lua_package(lua, &name, v)?;
+            Ok((name, pkg))
+        })
+        .collect()
+}
+
+/// Passed to `before_build`, right before Ninja or the native executor starts.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo<'a> {
+    pub build_dir: &'a str,
+    pub build_ninja: &'a str,
+    pub executor: &'static str,
+    /// Empty means everything.
+    pub targets: &'a [String],
+}
+
+/// Passed to `after_build`, also when the build failed.
+#[derive(Debug, Serialize)]
+pub struct BuildResult<'a> {
+    pub build_dir: &'a str,
+    pub success: bool,
+    pub exit_code: i32,
+    /// Seconds spent running the build graph.
+    pub duration: f64,
+    pub error: Option<String>,
+}
+
+/// `pkg` in a package's build.lua: the package as `graph.packages` has it, plus
+/// its name and type at the top level. Those two are read-only.
+#[derive(Serialize)]
+struct PackageScope<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(flatten)]
+    package: LuaPackage,
+}
+
+impl PackageScope<'_> {
+    fn new(node: &PkgNode) -> PackageScope<'_> {
+        PackageScope {
+            name: &node.name,
+            kind: &node.manifest.package.r#type,
+            package: LuaPackage::new(node),
+        }
+    }
+}
+
+// One loaded build.lua: the workspace's, or a package's with `pkg` in scope.
+struct Script {
+    lua: Lua,
+    /// `None` for the workspace's build.lua.
+    package: Option<String>,
+}
+
+impl Script {
+    fn load(
+        path: &Path,
+        chunk: &str,
+        package: Option<&PkgNode>,
+        root: &Path,
+        allow: &[String],
+    ) -> Result<Script> {
+        let lua_src = fs::read_to_string(path).with_context(|| format!("read {chunk}"))?;
+        let base = path.parent().unwrap_or(root);
+        let script = Script {
+            lua: sandbox(root, base, allow)?,
+            package: package.map(|n| n.name.clone()),
+        };
+        if let Some(node) = package {
+            script.set_pkg(node)?;
+        }
+        script
+            .lua
+            .load(&lua_src)
+            .set_name(chunk)
+            .exec()
+            .map_err(|e| anyhow!("{e}"))
+            .with_context(|| chunk.to_string())?;
+        Ok(script)
     }
 
-    let new_ctx: Ctx = lua.from_value(globals.get("ctx")?)?;
-    Ok(new_ctx)
+    fn set_pkg(&self, node: &PkgNode) -> Result<()> {
+        let opts = SerializeOptions::new().serialize_none_to_null(false);
+        let pkg = self.lua.to_value_with(&PackageScope::new(node), opts)?;
+        self.lua.globals().set("pkg", pkg)?;
+        Ok(())
+    }
+
+    fn call<R: DeserializeOwned>(
+        &self,
+        name: &str,
+        ctx: Ctx,
+        data: &impl Serialize,
+        as_pkg: bool,
+    ) -> Result<(Ctx, Option<R>)> {
+        self.call_with(name, ctx, data, as_pkg, |lua, v| lua.from_value(v))
+    }
+
+    // Calls `name(ctx, data)` if the script defines it. Returns `ctx` as the hook left
+    // it and, if the hook ran, the table it returned (or else `data` as it left it)
+    // passed through `read`. With `as_pkg`, `data` is also the global `pkg`.
+    fn call_with<T>(
+        &self,
+        name: &str,
+        ctx: Ctx,
+        data: &impl Serialize,
+        as_pkg: bool,
+        read: impl for<'l> FnOnce(&'l Lua, Value<'l>) -> mlua::Result<T>,
+    ) -> Result<(Ctx, Option<T>)> {
+        let lua = &self.lua;
+        let run = || -> mlua::Result<Option<(Ctx, T)>> {
+            let globals = lua.globals();
+            let Value::Function(f) = globals.get::<_, Value>(name)? else {
+                return Ok(None);
+            };
+            globals.set("ctx", lua.to_value(&ctx)?)?;
+            // absent fields are nil rather than the `null` sentinel
+            let data =
+                lua.to_value_with(data, SerializeOptions::new().serialize_none_to_null(false))?;
+            if as_pkg {
+                globals.set("pkg", data.clone())?;
+            }
+            let ret: Value = f.call((globals.get::<_, Value>("ctx")?, data.clone()))?;
+            let out = match ret {
+                Value::Table(_) => ret,
+                _ => data,
+            };
+            Ok(Some((
+                lua.from_value(globals.get("ctx")?)?,
+                read(lua, out)?,
+            )))
+        };
+        match run() {
+            Ok(Some((ctx, data))) => Ok((ctx, Some(data))),
+            Ok(None) => Ok((ctx, None)),
+            Err(e) => Err(anyhow!("{e}")).with_context(|| match &self.package {
+                Some(p) => format!("hook '{name}' of package '{p}' failed"),
+                None => format!("hook '{name}' failed"),
+            }),
+        }
+    }
+}
+
+/// The workspace's `build.lua` and those of its members, loaded once per command and
+/// called at each phase of `cmd_build`. The workspace's hooks run first. Without any
+/// `build.lua` every phase is a no-op.
+pub struct Hooks {
+    workspace: Option<Script>,
+    /// In member order.
+    packages: Vec<Script>,
+    root: PathBuf,
+}
+
+impl Hooks {
+    pub fn load(project_root: &str, allow: &[String], members: &[PkgNode]) -> Result<Hooks> {
+        for a in allow {
+            if !CAPABILITIES.contains(&a.as_str()) {
+                bail!(
+                    "[hooks] allow: unknown capability '{a}' (expected one of: {})",
+                    CAPABILITIES.join(", ")
+                );
+            }
+        }
+        let root = Path::new(project_root)
+            .canonicalize()
+            .with_context(|| format!("workspace root {project_root}"))?;
+        let path = root.join("build.lua");
+        let workspace = if path.exists() {
+            Some(Script::load(&path, "build.lua", None, &root, allow)?)
+        } else {
+            None
+        };
+        let mut packages = vec![];
+        for node in members {
+            let path = node.root.join("build.lua");
+            // a member at the workspace root shares its build.lua
+            if node.root == root || !path.exists() {
+                continue;
+            }
+            let chunk = pathdiff::diff_paths(&path, &root)
+                .unwrap_or_else(|| path.clone())
+                .display()
+                .to_string();
+            packages.push(Script::load(&path, &chunk, Some(node), &root, allow)?);
+        }
+        Ok(Hooks {
+            workspace,
+            packages,
+            root,
+        })
+    }
+
+    fn call<R: DeserializeOwned>(
+        &self,
+        name: &str,
+        ctx: Ctx,
+        data: &impl Serialize,
+    ) -> Result<(Ctx, Option<R>)> {
+        match &self.workspace {
+            Some(w) => w.call(name, ctx, data, false),
+            None => Ok((ctx, None)),
+        }
+    }
+
+    // Runs `name` in every package's build.lua; they see `ctx` but cannot change it.
+    fn call_packages(&self, name: &str, ctx: &Ctx, data: &impl Serialize) -> Result<()> {
+        for s in &self.packages {
+            s.call::<IgnoredAny>(name, ctx.clone(), data, false)?;
+        }
+        Ok(())
+    }
+
+    // A package as a hook left it. The key it was found under is its name.
+    fn read_package(&self, name: &str, p: LuaPackage) -> Result<PkgNode> {
+        let root = self.root.join(&p.root);
+        let node = PkgNode {
+            name: name.to_string(),
+            root: root.canonicalize().unwrap_or(root),
+            manifest: p.manifest,
+        };
+        assert_package(&node.manifest, &node.manifest_path())?;
+        Ok(node)
+    }
+
+    /// After the package manifests are loaded; may change `ctx`. Packages are
+    /// read-only here.
+    pub fn before_discover(&self, ctx: Ctx, packages: &[PkgNode]) -> Result<Ctx> {
+        let (ctx, _) = self.call::<IgnoredAny>("before_discover", ctx, &lua_packages(packages))?;
+        for s in &self.packages {
+            let Some(node) = packages
+                .iter()
+                .find(|n| s.package.as_ref() == Some(&n.name))
+            else {
+                continue;
+            };
+            s.call::<IgnoredAny>(
+                "before_discover",
+                ctx.clone(),
+                &PackageScope::new(node),
+                true,
+            )?;
+        }
+        Ok(ctx)
+    }
+
+    /// After the dependency graph is built, before build.ninja is generated. The
+    /// workspace hook may change `ctx` and edit, add or remove packages in
+    /// `graph.packages`; a package's own hook may edit `pkg`. An edited graph is
+    /// validated again as if it had been read from the manifests.
+    pub fn before_generate(&self, ctx: Ctx, graph: Graph) -> Result<(Ctx, Graph)> {
+        let view = GraphView {
+            packages: lua_packages(&graph.nodes),
+            order: graph
+                .build_order()
+                .iter()
+                .map(|&i| graph.nodes[i].name.as_str())
+                .collect(),
+        };
+        let (ctx, edit) = match &self.workspace {
+            Some(w) => w.call_with("before_generate", ctx, &view, false, lua_graph)?,
+            None => (ctx, None),
+        };
+        // a graph the hook only looked at is kept as it is
+        let edit = edit
+            .filter(|e| serde_json::to_value(e).ok() != serde_json::to_value(&view.packages).ok());
+        if edit.is_none() && self.packages.is_empty() {
+            return Ok((ctx, graph));
+        }
+        let mut nodes = match edit {
+            Some(mut edit) => {
+                // members keep their order, packages the hook added follow by name,
+                // so that build.ninja comes out the same on every run
+                let kept: Vec<_> = graph
+                    .nodes
+                    .iter()
+                    .filter_map(|n| edit.remove_entry(&n.name))
+                    .collect();
+                kept.into_iter()
+                    .chain(edit)
+                    .map(|(name, v)| self.read_package(&name, v))
+                    .collect::<Result<Vec<_>>>()
+                    .context("graph edited by hook 'before_generate'")?
+            }
+            None => graph.nodes,
+        };
+
+        for s in &self.packages {
+            // skipped if the workspace hook removed the package
+            let Some(node) = nodes
+                .iter_mut()
+                .find(|n| s.package.as_ref() == Some(&n.name))
+            else {
+                continue;
+            };
+            let name = node.name.clone();
+            let (_, edit) = s.call_with(
+                "before_generate",
+                ctx.clone(),
+                &PackageScope::new(node),
+                true,
+                |lua, v| lua_package(lua, &name, v),
+            )?;
+            if let Some(edit) = edit {
+                let mut edited = self
+                    .read_package(&node.name, edit)
+                    .with_context(|| format!("package '{}' edited by its build.lua", node.name))?;
+                // scoped to the package: it stays where it is
+                edited.root = node.root.clone();
+                *node = edited;
+            }
+            // later phases see the package as it will be built
+            s.set_pkg(node)?;
+        }
+        let graph = Graph::new(nodes).context("graph edited by hook 'before_generate'")?;
+        Ok((ctx, graph))
+    }
+
+    pub fn before_build(&self, ctx: &Ctx, info: &BuildInfo) -> Result<()> {
+        self.call::<IgnoredAny>("before_build", ctx.clone(), info)?;
+        self.call_packages("before_build", ctx, info)
+    }
+
+    pub fn after_build(&self, ctx: &Ctx, result: &BuildResult) -> Result<()> {
+        self.call::<IgnoredAny>("after_build", ctx.clone(), result)?;
+        self.call_packages("after_build", ctx, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("ghost-hooks-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&d);
+        fs::create_dir_all(d.join("ws/pkg")).unwrap();
+        d.canonicalize().unwrap()
+    }
+
+    // Runs `code` in a sandbox for the workspace `<dir>/ws`, from its `pkg` dir.
+    fn run(dir: &Path, allow: &[&str], code: &str) -> std::result::Result<String, String> {
+        let allow: Vec<String> = allow.iter().map(|a| a.to_string()).collect();
+        let lua = sandbox(&dir.join("ws"), &dir.join("ws/pkg"), &allow).unwrap();
+        lua.load(code)
+            .eval::<Option<String>>()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| e.to_string())
+    }
+
+    fn denied(r: std::result::Result<String, String>, msg: &str) {
+        let err = r.unwrap_err();
+        assert!(err.contains(msg), "{err:?} does not mention {msg:?}");
+    }
+
+    #[test]
+    fn system_access_needs_a_capability() {
+        let d = scratch("deny");
+        denied(
+            run(&d, &[], "exec('true')"),
+            "exec needs the 'exec' capability",
+        );
+        denied(
+            run(&d, &[], "os.execute('true')"),
+            "os.execute needs the 'exec' capability",
+        );
+        denied(
+            run(&d, &[], "fs.read('x')"),
+            "fs.read needs the 'fs.read' capability",
+        );
+        denied(
+            run(&d, &["fs.read"], "fs.write('x', '')"),
+            "needs the 'fs.write' capability",
+        );
+        denied(
+            run(&d, &["fs.read"], "io.open('x')"),
+            "io.open is not available in hooks",
+        );
+        denied(
+            run(&d, &[], "require('os')"),
+            "require is not available in hooks",
+        );
+        denied(
+            run(&d, &[], "dofile('x.lua')"),
+            "dofile needs the 'fs.read' capability",
+        );
+        denied(
+            run(&d, &[], "os.remove('x')"),
+            "os.remove is not available in hooks",
+        );
+        assert_eq!(run(&d, &[], "return type(os.time())").unwrap(), "number");
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn fs_stays_inside_the_workspace() {
+        let d = scratch("confine");
+        fs::write(d.join("secret"), "outside").unwrap();
+        fs::write(d.join("ws/top.txt"), "inside").unwrap();
+        let rw = ["fs.read", "fs.write"];
+        assert_eq!(
+            run(&d, &rw, "return fs.read('../top.txt')").unwrap(),
+            "inside"
+        );
+        denied(
+            run(&d, &rw, "return fs.read('../../secret')"),
+            "is outside the workspace",
+        );
+        denied(
+            run(&d, &rw, "fs.write('../../new', 'x')"),
+            "is outside the workspace",
+        );
+        run(&d, &rw, "fs.write('gen.h', '#pragma once')").unwrap();
+        assert!(d.join("ws/pkg/gen.h").exists());
+        assert_eq!(
+            run(&d, &rw, "return tostring(fs.exists('../../secret'))").unwrap(),
+            "false"
+        );
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    // `lib` (static, public define LIB=1) and `app` (exe, depends on lib) in
+    // `<dir>/ws`, with `lua` as the workspace build.lua.
+    fn generate(dir: &Path, lua: &str) -> Graph {
+        let ws = dir.join("ws");
+        fs::write(ws.join("build.lua"), lua).unwrap();
+        let node = |name: &str, manifest: &str| {
+            fs::create_dir_all(ws.join(name)).unwrap();
+            PkgNode {
+                name: name.into(),
+                root: ws.join(name),
+                manifest: toml::from_str(manifest).unwrap(),
+            }
+        };
+        let nodes = vec![
+            node(
+                "lib",
+                "[package]\nname = \"lib\"\ntype = \"static\"\n[sources]\nfiles = [\"lib.c\"]\n\
+                 [public]\ndefines = [\"LIB=1\"]\n",
+            ),
+            node(
+                "app",
+                "[package]\nname = \"app\"\ntype = \"exe\"\n[sources]\nfiles = [\"app.c\"]\n\
+                 [deps]\ndirect = [\"lib\"]\n",
+            ),
+        ];
+        let hooks = Hooks::load(ws.to_str().unwrap(), &[], &nodes).unwrap();
+        let graph = Graph::new(nodes).unwrap();
+        hooks.before_generate(Ctx::default(), graph).unwrap().1
+    }
+
+    fn lib_defines(g: &Graph) -> Option<&Vec<String>> {
+        g.nodes[0].manifest.public.as_ref()?.defines.as_ref()
+    }
+
+    fn names(g: &Graph) -> Vec<&str> {
+        g.nodes.iter().map(|n| n.name.as_str()).collect()
+    }
+
+    #[test]
+    fn edited_graph_keeps_member_order() {
+        let d = scratch("edit");
+        let g = generate(
+            &d,
+            r#"
+            function before_generate(ctx, graph)
+              graph.packages.lib.public.defines = {}
+              for _, name in ipairs({ "zz", "aa" }) do
+                graph.packages[name] = {
+                  root = "lib", package = { type = "static" }, sources = { files = { "lib.c" } },
+                }
+              end
+            end
+            "#,
+        );
+        assert_eq!(names(&g), ["lib", "app", "aa", "zz"]);
+        assert_eq!(lib_defines(&g), Some(&vec![]));
+        assert!(g.nodes[2].root.ends_with("ws/lib"));
+
+        // an untouched graph comes back as it was, whether returned or not
+        for lua in ["", "function before_generate(ctx, graph) return graph end"] {
+            let g = generate(&d, lua);
+            assert_eq!(names(&g), ["lib", "app"]);
+            assert_eq!(lib_defines(&g), Some(&vec!["LIB=1".to_string()]));
+        }
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn returned_graph_replaces_the_packages() {
+        let d = scratch("return");
+        let g = generate(
+            &d,
+            r#"
+            function before_generate(ctx, graph)
+              return { packages = { lib = graph.packages.lib } }
+            end
+            "#,
+        );
+        assert_eq!(names(&g), ["lib"]);
+        let _ = fs::remove_dir_all(&d);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fs_write_does_not_follow_symlinks_out() {
+        use std::os::unix::fs::symlink;
+        let d = scratch("symlink");
+        fs::write(d.join("secret"), "outside").unwrap();
+        symlink(d.join("secret"), d.join("ws/pkg/out")).unwrap();
+        symlink(d.join("missing"), d.join("ws/pkg/dangling")).unwrap();
+        symlink(d.join("ws/pkg/in.txt"), d.join("ws/pkg/in")).unwrap();
+        let rw = ["fs.write"];
+        denied(
+            run(&d, &rw, "fs.write('out', 'x')"),
+            "is outside the workspace",
+        );
+        denied(
+            run(&d, &rw, "fs.write('dangling', 'x')"),
+            "is a symlink to a missing file",
+        );
+        assert_eq!(fs::read_to_string(d.join("secret")).unwrap(), "outside");
+        fs::write(d.join("ws/pkg/in.txt"), "").unwrap();
+        run(&d, &rw, "fs.write('in', 'x')").unwrap();
+        assert_eq!(fs::read_to_string(d.join("ws/pkg/in.txt")).unwrap(), "x");
+        let _ = fs::remove_dir_all(&d);
+    }
 }
@@ -1,16 +1,26 @@
+mod cache;
+mod check;
+mod cli;
 mod context;
 mod discover;
+mod error;
+mod executor;
+mod graph;
 mod hooks;
 mod manifest;
 mod ninja;
 mod profile;
+mod remote;
+mod test_runner;
+mod ui;
 
 use anyhow::{bail, Context as _, Result};
-use context::{Ctx, Profile as CtxProfile};
-use manifest::{assert_package, load_package_manifest, load_root_manifest};
-use profile::{default_profile, load_profile};
+use clap::Parser as _;
+use context::Ctx;
+use manifest::{is_compile_src, load_package_manifest, load_root_manifest, GEN_DIR};
+use profile::{apply_profile, default_toolchain, load_profile, resolve_profile};
 use serde::Serialize;
-use std::{collections::HashMap, env, fs, path::Path, path::PathBuf};
+use std::{env, fs, path::Path, path::PathBuf};
 
 #[derive(Serialize)]
 struct CompileCommand {
@@ -20,88 +30,194 @@ struct CompileCommand {
     output: String,
 }
 
-#[derive(Clone, Default)]
-struct DepMeta {
-    root: std::path::PathBuf,
-    public_includes: Vec<String>,
-}
-
 fn write_compdb(root_dir: &str, entries: &[CompileCommand]) -> anyhow::Result<()> {
     let path = std::path::Path::new(root_dir).join("compile_commands.json");
     let json = serde_json::to_string_pretty(entries)?;
     std::fs::write(&path, json)?;
-    eprintln!("wrote {}", path.display());
+    ui::status!("wrote {}", path.display());
     Ok(())
 }
 
-fn is_compile_src(p: &str) -> bool {
-    matches!(
-        std::path::Path::new(p).extension().and_then(|s| s.to_str()),
-        Some("c" | "cc" | "cpp" | "cxx")
-    )
-}
-
-fn collect_dep_meta(members: &[String]) -> anyhow::Result<HashMap<String, DepMeta>> {
-    let mut map = HashMap::new();
-    for m in members {
-        let pkg_root = std::path::PathBuf::from(m).canonicalize()?;
-        let pkg = manifest::load_package_manifest(pkg_root.join("ghost.build").to_str().unwrap())?;
-        let pub_inc = pkg
-            .public
-            .as_ref()
-            .and_then(|p| p.include_dirs.clone())
-            .unwrap_or_default();
-        map.insert(
-            pkg.package.name.clone(),
-            DepMeta {
-                root: pkg_root,
-                public_includes: pub_inc,
-            },
+// Globs matched against source paths relative to the package and to the workspace root.
+fn exclude_set(patterns: &[String], origin: &str) -> Result<globset::GlobSet> {
+    let mut gb = globset::GlobSetBuilder::new();
+    for p in patterns {
+        gb.add(
+            globset::Glob::new(p).map_err(|source| error::GhostError::InvalidGlob {
+                origin: origin.to_string(),
+                source,
+            })?,
         );
     }
-    Ok(map)
+    Ok(gb.build()?)
 }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let cmd = args.get(1).map(|s| s.as_str()).unwrap_or("help");
-    match cmd {
-        "help" => help(),
-        "build" => cmd_build(args.get(2).map(|s| s.as_str()))?,
-        "discover" => cmd_discover()?,
-        _ => help(),
+fn lib_flag(l: &str) -> String {
+    if l.starts_with("-l") {
+        l.to_string()
+    } else {
+        format!("-l{}", l)
     }
-    Ok(())
 }
 
-fn help() {
-    println!(
-        "Ghost – minimal build orchestrator\nUsage: ghost [build|discover|help] [--profile <file>]"
-    );
+fn main() {
+    if let Err(e) = run() {
+        let typed = e
+            .chain()
+            .find_map(|c| c.downcast_ref::<error::GhostError>());
+        eprintln!("error: {e:#}");
+        if let Some(at) = typed.and_then(|g| g.location()) {
+            eprintln!("{at}");
+        }
+        std::process::exit(typed.map_or(1, |g| g.exit_code()));
+    }
 }
 
-fn load_profile_chain(opt: Option<&str>) -> Result<(context::Toolchain, CtxProfile)> {
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    // compile launcher used by the generated build.ninja; kept out of the CLI parser
+    if args.get(1).map(String::as_str) == Some("cache-exec") {
+        std::process::exit(cache::exec(&args[2..])?);
+    }
+
+    let cli = cli::Cli::parse();
+    let g = &cli.global;
+    if let Some(dir) = &g.directory {
+        env::set_current_dir(dir).with_context(|| format!("-C {}", dir.display()))?;
+    }
+    ui::set_level(if g.quiet {
+        ui::Level::Quiet
+    } else if g.verbose {
+        ui::Level::Verbose
+    } else {
+        ui::Level::Normal
+    });
+
+    match cli.cmd {
+        cli::Cmd::Build {
+            build,
+            legacy_profile,
+        } => {
+            let mut opts = BuildOpts::new(g, &build);
+            if opts.profile.is_none() {
+                opts.profile = legacy_profile;
+            }
+            cmd_build(&opts)?;
+        }
+        cli::Cmd::Test {
+            build,
+            filters,
+            timeout,
+            junit,
+        } => {
+            let mut opts = test_runner::TestOpts {
+                filters,
+                timeout: (timeout > 0).then(|| std::time::Duration::from_secs(timeout)),
+                junit,
+                ..Default::default()
+            };
+            if let Some(j) = g.jobs {
+                opts.jobs = j as usize;
+            }
+            cmd_test(&BuildOpts::new(g, &build), &opts)?;
+        }
+        cli::Cmd::Run {
+            package,
+            build,
+            cwd,
+            args,
+        } => cmd_run(BuildOpts::new(g, &build), package, cwd, &args)?,
+        cli::Cmd::Check { format } => cmd_check(format)?,
+        cli::Cmd::Clean { packages, all } => {
+            cmd_clean(g.builddir.as_deref(), g.config.as_deref(), &packages, all)?
+        }
+        cli::Cmd::Cache { cmd } => match cmd.unwrap_or(cli::CacheCmd::Stats) {
+            cli::CacheCmd::Stats => cache::stats()?,
+            cli::CacheCmd::Gc { max_size } => cache::gc(max_size.as_deref())?,
+            cli::CacheCmd::Clear => cache::clear()?,
+        },
+        cli::Cmd::CacheServer {
+            dir,
+            addr,
+            read_only,
+        } => remote::serve(&dir, &addr, read_only)?,
+    }
+    Ok(())
+}
+
+// Returns the toolchain and where it came from (for the build.ninja header).
+fn load_toolchain(opt: Option<&str>) -> Result<(context::Toolchain, String)> {
     if let Some(p) = opt {
-        return Ok((
-            load_profile(p)?.toolchain,
-            CtxProfile {
-                name: "custom".into(),
-                defines: vec![],
-                exclude: vec![],
-            },
-        ));
+        return Ok((load_profile(p)?.toolchain, p.to_string()));
     }
     if let Ok(p) = env::var("GHOST_PROFILE") {
-        return Ok((
-            load_profile(&p)?.toolchain,
-            CtxProfile {
-                name: "env".into(),
-                defines: vec![],
-                exclude: vec![],
-            },
-        ));
+        return Ok((load_profile(&p)?.toolchain, format!("{p} (GHOST_PROFILE)")));
+    }
+    Ok((default_toolchain(), "built-in default".into()))
+}
+
+/// `--builddir`, then `[builddir] out-of-tree`, then `[builddir] dir`, then `build`.
+fn base_build_dir(root: &manifest::ProjectRoot, cli: Option<&str>) -> Result<String> {
+    if let Some(d) = cli {
+        return Ok(d.trim_end_matches('/').to_string());
+    }
+    let Some(b) = &root.builddir else {
+        return Ok("build".into());
+    };
+    if let Some(d) = &b.out_of_tree {
+        if !Path::new(d).is_absolute() {
+            bail!("[builddir] out-of-tree must be an absolute path, got '{d}'");
+        }
+        return Ok(d.trim_end_matches('/').to_string());
+    }
+    Ok(b.dir
+        .as_deref()
+        .map(|d| d.trim_end_matches('/'))
+        .filter(|d| !d.is_empty())
+        .unwrap_or("build")
+        .to_string())
+}
+
+// `target_triple` from the toolchain file, or `<arch>-<os>` of the host.
+fn target_triple(tc: &context::Toolchain) -> String {
+    match tc.target_triple.as_deref().map(str::trim) {
+        Some(t) if !t.is_empty() => t.to_string(),
+        _ => format!("{}-{}", env::consts::ARCH, env::consts::OS),
+    }
+}
+
+struct BuildOpts {
+    /// Toolchain file (`ghost.profile`).
+    profile: Option<String>,
+    /// `--builddir`, overrides `[builddir]` from the root manifest.
+    builddir: Option<String>,
+    /// Name of the `[profile.<name>]` fragment from the root `ghost.build`.
+    config: String,
+    /// Command line overrides, layered on top of the selected profile.
+    overrides: manifest::ProfileFrag,
+    /// `--executor native|ninja|auto`.
+    executor: executor::Executor,
+    /// `-j`, parallel jobs for the executor (and for `ghost test`).
+    jobs: Option<usize>,
+    /// `--no-cache`: compile without the local object cache.
+    no_cache: bool,
+    /// Build only these packages and their dependencies (all when empty).
+    packages: Vec<String>,
+}
+
+impl BuildOpts {
+    fn new(g: &cli::GlobalOpts, b: &cli::BuildArgs) -> Self {
+        Self {
+            profile: g.profile.clone(),
+            builddir: g.builddir.clone(),
+            config: g.config.clone().unwrap_or_else(|| "debug".into()),
+            overrides: b.overrides(),
+            executor: b.executor,
+            jobs: g.jobs.map(|j| j as usize),
+            no_cache: b.no_cache,
+            packages: b.packages.clone(),
+        }
     }
-    Ok(default_profile())
 }
 
 fn base_ctx() -> Result<Ctx> {
@@ -121,91 +237,289 @@ fn base_ctx() -> Result<Ctx> {
     })
 }
 
-fn cmd_discover() -> Result<()> {
-    let root = load_root_manifest("ghost.build").context("load root ghost.build")?;
-    println!(
-        "Build dir: {}",
-        root.build_dir
-            .as_ref()
-            .map(|b| b.dir.clone())
-            .unwrap_or_else(|| "build".into())
-    );
-    println!("Targets: ");
+// Everything ghost writes into a `<builddir>/<triple>/<config>/` directory.
+const BUILD_OUTPUTS: &[&str] = &[
+    "obj",
+    "lib",
+    "bin",
+    "tests",
+    "build.ninja",
+    ".ghost_log",
+    ".ninja_log",
+    ".ninja_deps",
+    cache::BUILD_LOG,
+];
 
-    let members = root
-        .workspace
-        .ok_or_else(|| anyhow::anyhow!("workspace.members missing"))?
-        .members;
+// First line of every build.ninja ghost writes; `ghost clean` only touches config
+// directories whose build.ninja starts with it.
+const NINJA_HEADER: &str = "# generated by ghost";
 
-    for m in members {
-        let pkg_root = PathBuf::from(&m).canonicalize()?;
-        let pkg = load_package_manifest(pkg_root.join("ghost.build").to_str().unwrap())?;
-        manifest::assert_package(&pkg)?;
+fn generated_by_ghost(build_ninja: &Path) -> bool {
+    use std::io::{BufRead, BufReader};
+    let mut first = String::new();
+    fs::File::open(build_ninja)
+        .and_then(|f| BufReader::new(f).read_line(&mut first))
+        .is_ok()
+        && first.starts_with(NINJA_HEADER)
+}
 
-        let mut missing = Vec::new();
-        let mut total = 0usize;
-        let mut compilable = 0usize;
+fn remove_path(p: &Path, removed: &mut usize) -> Result<()> {
+    let res = if p.is_dir() {
+        fs::remove_dir_all(p)
+    } else if p.exists() || p.is_symlink() {
+        fs::remove_file(p)
+    } else {
+        return Ok(());
+    };
+    res.with_context(|| format!("remove {}", p.display()))?;
+    ui::status!("removed {}", p.display());
+    *removed += 1;
+    Ok(())
+}
 
-        for f in &pkg.sources.files {
-            total += 1;
-            let exists = pkg_root.join(f).exists();
-            if !exists {
-                missing.push(f.clone());
+fn cmd_clean(
+    builddir: Option<&str>,
+    config: Option<&str>,
+    packages: &[String],
+    all: bool,
+) -> Result<()> {
+    let root = load_root_manifest("ghost.build")?;
+    let base = PathBuf::from(base_build_dir(&root, builddir)?);
+    let members = root
+        .workspace
+        .as_ref()
+        .map(|w| w.members.clone())
+        .unwrap_or_default();
+    // (name, root, [[generate]] outputs) of each member; a broken manifest must not
+    // stop a clean
+    let pkgs: Vec<(Option<String>, PathBuf, Vec<String>)> = members
+        .iter()
+        .map(|m| {
+            let dir = PathBuf::from(m);
+            match load_package_manifest(dir.join("ghost.build").to_str().unwrap()) {
+                Ok(p) => (Some(p.package.name.clone()), dir, p.generated()),
+                Err(_) => (None, dir, vec![]),
             }
-            if is_compile_src(f) {
-                compilable += 1;
+        })
+        .collect();
+    for p in packages {
+        if !pkgs.iter().any(|(n, ..)| n.as_deref() == Some(p)) {
+            let known: Vec<&str> = pkgs.iter().filter_map(|(n, ..)| n.as_deref()).collect();
+            bail!(
+                "unknown package '{p}' (workspace packages: {})",
+                known.join(", ")
+            );
+        }
+    }
+
+    // only directories ghost generated (their build.ninja has ghost's header) are touched
+    let mut config_dirs = vec![];
+    for triple in fs::read_dir(&base).into_iter().flatten().flatten() {
+        for cfg in fs::read_dir(triple.path()).into_iter().flatten().flatten() {
+            let dir = cfg.path();
+            let selected = config.is_none_or(|c| cfg.file_name().to_str() == Some(c));
+            if selected && generated_by_ghost(&dir.join("build.ninja")) {
+                config_dirs.push(dir);
             }
         }
+    }
 
-        if missing.is_empty() {
-            println!(
-                "{}: {} files ({} compilable) – OK",
-                pkg.package.name, total, compilable
-            );
+    let mut removed = 0usize;
+    for dir in &config_dirs {
+        if packages.is_empty() {
+            for o in BUILD_OUTPUTS {
+                remove_path(&dir.join(o), &mut removed)?;
+            }
+            // drop the now empty <config>/ and <triple>/ dirs, never the base itself
+            let _ = fs::remove_dir(dir);
+            if let Some(t) = dir.parent() {
+                let _ = fs::remove_dir(t);
+            }
         } else {
-            eprintln!("{}: missing {} file(s):", pkg.package.name, missing.len());
-            for x in missing {
-                eprintln!("  - {}", x);
+            for p in packages {
+                remove_path(&dir.join("obj").join(p), &mut removed)?;
+            }
+        }
+    }
+    for (name, dir, generated) in &pkgs {
+        if packages.is_empty() || name.as_ref().is_some_and(|n| packages.contains(n)) {
+            remove_path(&dir.join(".ghost"), &mut removed)?;
+            // generated files are shared by all configs; anything else in .gen/ stays
+            if config.is_none() {
+                let gen = dir.join(GEN_DIR);
+                for f in generated {
+                    let path = dir.join(f);
+                    remove_path(&path, &mut removed)?;
+                    // and the directories that left empty, up to .gen/ itself
+                    for d in path.ancestors().skip(1).take_while(|d| d.starts_with(&gen)) {
+                        let _ = fs::remove_dir(d);
+                    }
+                }
             }
-            std::process::exit(2);
         }
     }
+    if all {
+        remove_path(Path::new("compile_commands.json"), &mut removed)?;
+        remove_path(&cache::cache_dir(), &mut removed)?;
+    }
+    if removed == 0 {
+        ui::status!("nothing to clean");
+    }
     Ok(())
 }
 
-fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
-    let (tc, prof) = load_profile_chain(profile_arg)?;
-    let mut ctx = base_ctx()?;
-    let ws_root = ctx.workspace_root.clone();
-    let mut ccdb: Vec<CompileCommand> = Vec::new();
-    ctx.toolchain = tc;
-    ctx.profile = prof;
-    ctx = hooks::run_lua_hooks(ctx, &ws_root)?;
-
-    let root = load_root_manifest("ghost.build").context("load root ghost.build")?;
-    let build_dir = root
-        .build_dir
-        .as_ref()
-        .map(|b| b.dir.clone())
-        .unwrap_or_else(|| "build".into());
+fn cmd_check(format: cli::Format) -> Result<()> {
+    let root = load_root_manifest("ghost.build")?;
     let members = root
         .workspace
         .ok_or_else(|| anyhow::anyhow!("workspace.members missing"))?
         .members;
+    let problems = check::check(&base_ctx()?, &members);
+    match format {
+        cli::Format::Human => check::print_human(&problems, members.len()),
+        cli::Format::Json => check::print_json(&problems)?,
+    }
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == check::Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!("workspace check found {errors} error(s)");
+    }
+    Ok(())
+}
+
+// Records the fully resolved configuration, so a build.ninja can be traced back to
+// the toolchain file, project profile and CLI overrides that produced it.
+fn emit_header(nin: &mut ninja::NinjaBuf, ctx: &Ctx, toolchain_src: &str) {
+    let p = &ctx.profile;
+    let tc = &ctx.toolchain;
+    nin.push(&format!("{NINJA_HEADER}, do not edit"));
+    nin.push(&format!("# config:    {}", p.name));
+    nin.push(&format!("# toolchain: {}", toolchain_src));
+    nin.push(&format!(
+        "# opt_level: {}  debug_info: {}  lto: {}  sanitizers: [{}]",
+        p.opt_level.as_deref().unwrap_or("-"),
+        p.debug_info.map_or("-".to_string(), |d| d.to_string()),
+        p.lto.as_deref().unwrap_or("off"),
+        p.sanitizers.join(", ")
+    ));
+    nin.push(&format!("# cflags:    {}", tc.cflags.join(" ")));
+    nin.push(&format!("# cxxflags:  {}", tc.cxxflags.join(" ")));
+    nin.push(&format!("# ldflags:   {}", tc.ldflags.join(" ")));
+    nin.push(&format!("# defines:   {}", p.defines.join(" ")));
+    nin.push("");
+}
 
-    let dep_map = collect_dep_meta(&members)?;
+struct BuildSummary {
+    build_dir: String,
+    tests: Vec<test_runner::TestCase>,
+    /// Executables by package name.
+    binaries: std::collections::HashMap<String, PathBuf>,
+}
+
+// The workspace as it is going to be built: the root manifest, toolchain and profile,
+// and the package graph once the `before_discover` and `before_generate` hooks ran.
+struct Workspace {
+    root: manifest::ProjectRoot,
+    ctx: Ctx,
+    toolchain_src: String,
+    hooks: hooks::Hooks,
+    graph: graph::Graph,
+}
+
+fn load_workspace(opts: &BuildOpts) -> Result<Workspace> {
+    let root = load_root_manifest("ghost.build")?;
+    let mut ctx = base_ctx()?;
+    let (toolchain, toolchain_src) = load_toolchain(opts.profile.as_deref())?;
+    ctx.toolchain = toolchain;
+    ctx.profile = resolve_profile(root.profile.as_ref(), &opts.config, &opts.overrides)?;
+    let members = &root
+        .workspace
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("workspace.members missing"))?
+        .members;
+
+    let nodes = graph::load_members(members)?;
+    let allow = root
+        .hooks
+        .as_ref()
+        .map(|h| h.allow.as_slice())
+        .unwrap_or_default();
+    let hooks = hooks::Hooks::load(&ctx.workspace_root, allow, &nodes)?;
+    ctx = hooks.before_discover(ctx, &nodes)?;
+    let (mut ctx, graph) = hooks.before_generate(ctx, graph::Graph::new(nodes)?)?;
+    apply_profile(&mut ctx.toolchain, &ctx.profile);
+    Ok(Workspace {
+        root,
+        ctx,
+        toolchain_src,
+        hooks,
+        graph,
+    })
+}
+
+fn cmd_build(opts: &BuildOpts) -> Result<BuildSummary> {
+    build_workspace(load_workspace(opts)?, opts)
+}
+
+fn build_workspace(ws: Workspace, opts: &BuildOpts) -> Result<BuildSummary> {
+    let Workspace {
+        root,
+        ctx,
+        toolchain_src,
+        hooks,
+        graph,
+    } = ws;
+    let ws_root = ctx.workspace_root.clone();
+    let mut ccdb: Vec<CompileCommand> = Vec::new();
+
+    // each toolchain/config pair gets its own tree so outputs never mix
+    let build_dir = format!(
+        "{}/{}/{}",
+        base_build_dir(&root, opts.builddir.as_deref())?,
+        target_triple(&ctx.toolchain),
+        ctx.profile.name
+    );
+    let exclude = exclude_set(
+        &ctx.profile.exclude,
+        &format!("[profile.{}] exclude", ctx.profile.name),
+    )?;
+    let selected = select_packages(&graph, &opts.packages)?;
 
     let mut nin = ninja::NinjaBuf::new();
+    emit_header(&mut nin, &ctx, &toolchain_src);
     ninja::emit_prelude(&mut nin);
     nin.push(&format!("builddir = {}", build_dir));
     nin.push(&format!("cc = {}", ctx.toolchain.cc));
     nin.push(&format!("cxx = {}", ctx.toolchain.cxx));
     nin.push(&format!("ar = {}", ctx.toolchain.ar));
+    let use_cache = cache::enabled(opts.no_cache);
+    let remote = match use_cache {
+        true => cache::remote_config(root.cache.as_ref())?,
+        false => None,
+    };
+    if use_cache {
+        nin.push(&format!(
+            "launcher = {}",
+            cache::launcher(&build_dir, remote.as_ref())?
+        ));
+    } else {
+        nin.push("launcher =");
+    }
     let arflags = ctx.toolchain.arflags.clone().unwrap_or_default().join(" ");
     nin.push(&format!("arflags = {}", arflags));
     nin.push(&format!("cflags = {}", ctx.toolchain.cflags.join(" ")));
     nin.push(&format!("cxxflags = {}", ctx.toolchain.cxxflags.join(" ")));
     nin.push(&format!("ldflags = {}", ctx.toolchain.ldflags.join(" ")));
+    let profile_defs = ctx
+        .profile
+        .defines
+        .iter()
+        .map(|d| format!("-D{}", d))
+        .collect::<Vec<_>>()
+        .join(" ");
+    nin.push(&format!("defines = {}", profile_defs));
 
     let libdirs = ctx
         .toolchain
@@ -223,14 +537,8 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
         .libs
         .clone()
         .unwrap_or_default()
-        .into_iter()
-        .map(|l| {
-            if l.starts_with("-l") {
-                l
-            } else {
-                format!("-l{}", l)
-            }
-        })
+        .iter()
+        .map(|l| lib_flag(l))
         .collect::<Vec<_>>()
         .join(" ");
     nin.push(&format!("libs = {}", libs));
@@ -247,13 +555,13 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
             if let Some(f) = ctx.toolchain.fuse_ld.as_deref() {
                 nin.push(&format!("linkflags = -fuse-ld={}", f));
             } else {
-                nin.push("linkflags =".into());
+                nin.push("linkflags =");
             }
         }
         "ld" => {
             let linker = ctx.toolchain.link.clone().unwrap_or_else(|| "ld".into());
             nin.push(&format!("link = {}", linker));
-            nin.push("linkflags =".into());
+            nin.push("linkflags =");
         }
         "msvc" => {
             let linker = ctx
@@ -262,12 +570,12 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
                 .clone()
                 .unwrap_or_else(|| "link".into());
             nin.push(&format!("link = {}", linker));
-            nin.push("linkflags =".into());
+            nin.push("linkflags =");
         }
         _ => {
             // fallback: driver
             nin.push(&format!("link = {}", ctx.toolchain.cxx));
-            nin.push("linkflags =".into());
+            nin.push("linkflags =");
         }
     }
     nin.push("");
@@ -275,6 +583,7 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
     fs::create_dir_all(format!("{}/obj", build_dir)).ok();
     fs::create_dir_all(format!("{}/lib", build_dir)).ok();
     fs::create_dir_all(format!("{}/bin", build_dir)).ok();
+    fs::create_dir_all(format!("{}/tests", build_dir)).ok();
 
     let mut libdirs_vec = ctx.toolchain.libdirs.clone().unwrap_or_default();
     let default_libdir = format!("{}/lib", build_dir);
@@ -293,72 +602,142 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
         .libs
         .clone()
         .unwrap_or_default()
-        .into_iter()
-        .map(|l| {
-            if l.starts_with("-l") {
-                l
-            } else {
-                format!("-l{}", l)
-            }
-        })
+        .iter()
+        .map(|l| lib_flag(l))
         .collect::<Vec<_>>()
         .join(" ");
     nin.push(&format!("libs = {}", libs));
     nin.push("");
 
-    let mut built_libs: Vec<String> = vec![];
-    for m in members {
-        let pkg_root = PathBuf::from(&m).canonicalize()?;
-        let pkg = load_package_manifest(pkg_root.join("ghost.build").to_str().unwrap())?;
-        assert_package(&pkg)?;
+    let needs_pic = graph.needs_pic();
+    let mut tests: Vec<test_runner::TestCase> = vec![];
+    let mut binaries = std::collections::HashMap::new();
+    // build.ninja outputs of each package's artifact(s)
+    let mut outputs: Vec<Vec<String>> = vec![vec![]; graph.nodes.len()];
+    for &idx in graph.build_order() {
+        let node = &graph.nodes[idx];
+        let pkg = &node.manifest;
+        let pkg_root = &node.root;
+
+        let generated = pkg.generated();
+        let missing: Vec<String> = pkg
+            .sources
+            .files
+            .iter()
+            .chain(pkg.generate.iter().flat_map(|g| &g.inputs))
+            .filter(|f| !generated.contains(f) && !pkg_root.join(f).exists())
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(error::GhostError::MissingSource {
+                package: pkg.package.name.clone(),
+                files: missing,
+            }
+            .into());
+        }
 
-        let pkg_obj_dir = format!("build/obj/{}", pkg.package.name);
+        let pkg_obj_dir = format!("{}/obj/{}", build_dir, pkg.package.name);
         std::fs::create_dir_all(&pkg_obj_dir).ok();
 
+        if !pkg.generate.is_empty() {
+            // exists up front so that it lands on the include path
+            fs::create_dir_all(pkg_root.join(GEN_DIR)).ok();
+        }
+        for g in &pkg.generate {
+            let ins: Vec<String> = g
+                .inputs
+                .iter()
+                .map(|f| format!("{}/{}", pkg_root.display(), f))
+                .collect();
+            let outs: Vec<String> = g
+                .outputs
+                .iter()
+                .map(|f| format!("{}/{}/{}", pkg_root.display(), GEN_DIR, f))
+                .collect();
+            let cmd = ninja::expand(&g.command, &|v| match v {
+                "in" => Some(ins.join(" ")),
+                "out" => Some(outs.join(" ")),
+                _ => None,
+            });
+            nin.push(&format!("build {}: gen {}", outs.join(" "), ins.join(" ")));
+            nin.push(&format!("  dir = {}", pkg_root.display()));
+            nin.push(&format!("  cmd = {}", ninja::escape(&cmd)));
+        }
+        let gen_headers = generated_headers(&graph, idx);
+        let order_only = match gen_headers.is_empty() {
+            true => String::new(),
+            false => format!(" || {}", gen_headers.join(" ")),
+        };
+
         use std::collections::BTreeMap;
         let mut unit_map: BTreeMap<String, String> = BTreeMap::new();
+        let pic = needs_pic[idx];
+        let mut excluded = 0usize;
 
-        for f in &pkg.sources.files {
+        // generated sources are compiled without being listed
+        let units = pkg
+            .sources
+            .files
+            .iter()
+            .chain(generated.iter().filter(|f| !pkg.sources.files.contains(f)));
+        for f in units {
             if !is_compile_src(f) {
                 continue;
             }
+            let ws_rel = pathdiff::diff_paths(pkg_root.join(f), &ws_root)
+                .map(|p| p.to_string_lossy().replace('\\', "/"))
+                .unwrap_or_else(|| f.clone());
+            if exclude.is_match(f) || exclude.is_match(&ws_rel) {
+                excluded += 1;
+                continue;
+            }
 
             let rule = if f.ends_with(".c") { "cc" } else { "cxx" };
-            let pkg_obj_dir = format!("{}/obj/{}", build_dir, pkg.package.name);
-            std::fs::create_dir_all(&pkg_obj_dir).ok();
 
             let obj = format!(
                 "{}/{}.o",
                 pkg_obj_dir,
                 f.replace(['/', '\\'], "_").replace('.', "_")
             );
-            let inc = include_dirs_vars(&pkg, &pkg_root, &dep_map);
-            let src_abs = pkg_root
-                .join(&f)
-                .canonicalize()
-                .unwrap_or(pkg_root.join(&f));
+            let inc = include_dirs_vars(&graph, idx);
+            let defs = defines_vars(&graph, idx);
+            let src_abs = pkg_root.join(f).canonicalize().unwrap_or(pkg_root.join(f));
             let obj_abs = std::path::Path::new(&obj)
                 .canonicalize()
                 .unwrap_or(std::path::PathBuf::from(&obj));
-            let (compiler, flags) = if rule == "cc" {
+            let (compiler, mut flags) = if rule == "cc" {
                 (ctx.toolchain.cc.clone(), ctx.toolchain.cflags.clone())
             } else {
                 (ctx.toolchain.cxx.clone(), ctx.toolchain.cxxflags.clone())
             };
+            if pic {
+                flags.push("-fPIC".into());
+            }
             // TODO add MSVC
             // cl /nologo /showIncludes <FLAGS> <INCLUDES> /c <FILE> /Fo<OBJ>
             let command = format!(
-                "{} -MMD -MF {}.d {} {} -c {} -o {}",
+                "{} -MMD -MF {}.d {} {} {} -c {} -o {}",
                 compiler,
                 obj,
                 flags.join(" "),
+                [profile_defs.as_str(), defs.as_str()].join(" ").trim(),
                 inc,
                 src_abs.display(),
                 obj_abs.display(),
             );
 
-            nin.push(&format!("build {obj}: {rule} {}/{}", pkg_root.display(), f));
+            nin.push(&format!(
+                "build {obj}: {rule} {}/{}{}",
+                pkg_root.display(),
+                f,
+                order_only
+            ));
+            nin.push(&format!("  defines = $defines {}", defs));
             nin.push(&format!("  includes = {}", inc));
+            if pic {
+                let var = if rule == "cc" { "cflags" } else { "cxxflags" };
+                nin.push(&format!("  {var} = ${var} -fPIC"));
+            }
             unit_map.insert(f.clone(), obj);
 
             ccdb.push(CompileCommand {
@@ -370,12 +749,24 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
         }
 
         let objs: Vec<String> = unit_map.values().cloned().collect();
-        if objs.is_empty() {
-            eprintln!(
-                "error: package '{}' has no compilable sources in [sources.files]",
-                pkg.package.name
+        if objs.is_empty() && excluded > 0 && matches!(pkg.package.r#type.as_str(), "exe" | "test")
+        {
+            ui::status!(
+                "note: skipping '{}': all sources excluded by [profile.{}]",
+                pkg.package.name,
+                ctx.profile.name
             );
-            std::process::exit(2);
+            continue;
+        }
+        if objs.is_empty() && pkg.package.r#type != "interface" {
+            return Err(error::GhostError::Manifest {
+                message: format!(
+                    "package '{}' has no compilable sources in [sources.files]",
+                    pkg.package.name
+                ),
+                at: error::Location::new(&node.manifest_path(), "", None),
+            }
+            .into());
         }
 
         match pkg.package.r#type.as_str() {
@@ -386,12 +777,64 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
                 } else {
                     nin.push(&format!("build {}: ar {}", out, objs.join(" ")));
                 }
-                built_libs.push(out);
+                outputs[idx].push(out);
             }
-            "exe" => {
-                let mut inputs = objs.clone();
-                inputs.extend(built_libs.clone());
-                let out = format!("{}/bin/{}", build_dir, pkg.package.name);
+            "shared" => {
+                if ctx.toolchain.link_mode.as_deref() == Some("msvc") {
+                    bail!(
+                        "package '{}': shared libraries are not supported with link_mode = \"msvc\"",
+                        pkg.package.name
+                    );
+                }
+                let names =
+                    shared_lib_names(&pkg.package.name, pkg.package.version.as_deref(), &ctx.os);
+                let lib_dir = format!("{}/lib", build_dir);
+                let real = format!("{}/{}", lib_dir, names.real);
+                let link_rule = if ctx.os == "macos" {
+                    "link_shared_macos"
+                } else {
+                    "link_shared"
+                };
+                let (inputs, links_shared) = link_inputs(&graph, idx, &objs, &build_dir, &ctx.os);
+                nin.push(&format!(
+                    "build {}: {} {}",
+                    real,
+                    link_rule,
+                    inputs.join(" ")
+                ));
+                nin.push(&format!("  soname = {}", names.soname));
+                outputs[idx].push(real.clone());
+                push_link_flags(&mut nin, &graph, idx);
+                if links_shared {
+                    nin.push(&format!(
+                        "  ldflags = $ldflags {}",
+                        rpath_flag(&ctx.os, ".")
+                    ));
+                }
+                // libfoo.so -> libfoo.so.1 -> libfoo.so.1.2.3
+                let mut target = names.real.clone();
+                for alias in [&names.soname, &names.link] {
+                    if *alias == target {
+                        continue;
+                    }
+                    nin.push(&format!(
+                        "build {}/{}: symlink {}/{}",
+                        lib_dir, alias, lib_dir, target
+                    ));
+                    nin.push(&format!("  target = {}", target));
+                    outputs[idx].push(format!("{}/{}", lib_dir, alias));
+                    target = alias.clone();
+                }
+            }
+            "exe" | "test" => {
+                let (inputs, links_shared) = link_inputs(&graph, idx, &objs, &build_dir, &ctx.os);
+                let is_test = pkg.package.r#type == "test";
+                let out = format!(
+                    "{}/{}/{}",
+                    build_dir,
+                    if is_test { "tests" } else { "bin" },
+                    pkg.package.name
+                );
                 let link_rule = if ctx.toolchain.link_mode.as_deref() == Some("msvc") {
                     "link_exe_msvc"
                 } else {
@@ -403,10 +846,27 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
                     link_rule,
                     inputs.join(" ")
                 ));
-                nin.push(&format!("  libdirs = -L{}/lib", build_dir));
+                push_link_flags(&mut nin, &graph, idx);
+                if links_shared {
+                    nin.push(&format!(
+                        "  ldflags = $ldflags {}",
+                        rpath_flag(&ctx.os, "../lib")
+                    ));
+                }
+                outputs[idx].push(out.clone());
+                if !is_test {
+                    binaries.insert(pkg.package.name.clone(), PathBuf::from(&out));
+                }
+                if is_test && selected[idx] {
+                    tests.push(test_runner::TestCase {
+                        name: pkg.package.name.clone(),
+                        path: PathBuf::from(&out),
+                        cwd: pkg_root.clone(),
+                    });
+                }
             }
-            "interface" | "shared" | "test" => {
-                // TODO
+            "interface" => {
+                // header-only: usage requirements are picked up by dependents via the graph
             }
             other => eprintln!("warn: unsupported package type '{}'", other),
         }
@@ -417,22 +877,339 @@ fn cmd_build(profile_arg: Option<&str>) -> Result<()> {
 
     write_compdb(&ws_root, &ccdb)?;
 
-    // ninja z użyciem -f, żeby nie musieć chdir
-    let status = std::process::Command::new("ninja")
-        .args(["-f", &build_ninja_path])
-        .status()
-        .context("run ninja")?;
-    if !status.success() {
-        bail!("ninja failed");
+    let targets: Vec<String> = match opts.packages.is_empty() {
+        true => vec![],
+        false => (0..graph.nodes.len())
+            .filter(|&i| selected[i])
+            .flat_map(|i| outputs[i].iter().cloned())
+            .collect(),
+    };
+    if !opts.packages.is_empty() && targets.is_empty() {
+        ui::status!("nothing to build for {}", opts.packages.join(", "));
+        return Ok(BuildSummary {
+            build_dir,
+            tests,
+            binaries,
+        });
+    }
+
+    // hit/miss log of the previous (interrupted) build
+    fs::remove_file(format!("{}/{}", build_dir, cache::BUILD_LOG)).ok();
+    fs::remove_file(format!(
+        "{}/{}{}",
+        build_dir,
+        cache::BUILD_LOG,
+        cache::REMOTE_DOWN_SUFFIX
+    ))
+    .ok();
+    let use_ninja = match opts.executor {
+        executor::Executor::Ninja => true,
+        executor::Executor::Native => false,
+        executor::Executor::Auto => executor::ninja_on_path(),
+    };
+    hooks.before_build(
+        &ctx,
+        &hooks::BuildInfo {
+            build_dir: &build_dir,
+            build_ninja: &build_ninja_path,
+            executor: if use_ninja { "ninja" } else { "native" },
+            targets: &targets,
+        },
+    )?;
+    let started = std::time::Instant::now();
+    let mut exit_code = 0;
+    let built = if use_ninja {
+        // ninja z użyciem -f, żeby nie musieć chdir
+        let mut cmd = std::process::Command::new("ninja");
+        cmd.args(["-f", &build_ninja_path]);
+        if let Some(j) = opts.jobs {
+            cmd.args(["-j", &j.to_string()]);
+        }
+        match ui::level() {
+            ui::Level::Verbose => {
+                cmd.arg("-v");
+            }
+            ui::Level::Quiet => {
+                cmd.arg("--quiet");
+            }
+            ui::Level::Normal => {}
+        }
+        cmd.args(&targets);
+        match cmd.status().context("run ninja") {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => {
+                exit_code = status.code().unwrap_or(1);
+                Err(anyhow::anyhow!("ninja failed"))
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        let jobs = opts.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        });
+        executor::run(&nin, &build_dir, jobs, &targets)
+    };
+    if built.is_err() && exit_code == 0 {
+        exit_code = 1;
+    }
+    let after = hooks.after_build(
+        &ctx,
+        &hooks::BuildResult {
+            build_dir: &build_dir,
+            success: built.is_ok(),
+            exit_code,
+            duration: started.elapsed().as_secs_f64(),
+            error: built.as_ref().err().map(|e| format!("{e:#}")),
+        },
+    );
+    built?;
+    after?;
+    if use_cache {
+        cache::report_build(&build_dir, remote.as_ref());
+    }
+    Ok(BuildSummary {
+        build_dir,
+        tests,
+        binaries,
+    })
+}
+
+// The named packages and everything they depend on; every package when `names` is empty.
+fn select_packages(graph: &graph::Graph, names: &[String]) -> Result<Vec<bool>> {
+    let mut selected = vec![names.is_empty(); graph.nodes.len()];
+    for n in names {
+        let Some(idx) = graph.nodes.iter().position(|p| &p.name == n) else {
+            let known: Vec<&str> = graph.nodes.iter().map(|p| p.name.as_str()).collect();
+            bail!(
+                "unknown package '{n}' (workspace packages: {})",
+                known.join(", ")
+            );
+        };
+        selected[idx] = true;
+        for d in graph.closure(idx) {
+            selected[d] = true;
+        }
+    }
+    Ok(selected)
+}
+
+fn cmd_test(build: &BuildOpts, opts: &test_runner::TestOpts) -> Result<()> {
+    let summary = cmd_build(build)?;
+    let results = test_runner::run_tests(&summary.tests, opts)?;
+    if let Some(path) = &opts.junit {
+        test_runner::write_junit(path, &results)?;
+        ui::status!("wrote {}", path.display());
+    }
+    if !test_runner::print_summary(&results) {
+        std::process::exit(1);
     }
     Ok(())
 }
 
-fn include_dirs_vars(
-    pkg: &manifest::PackageManifest,
-    pkg_root: &Path,
-    dep_map: &HashMap<String, DepMeta>,
-) -> String {
+fn cmd_run(
+    mut build: BuildOpts,
+    mut package: Option<String>,
+    cwd: Option<PathBuf>,
+    run_args: &[String],
+) -> Result<()> {
+    // packages added or renamed by build.lua can be run too
+    let ws = load_workspace(&build)?;
+    let graph = &ws.graph;
+    let exes: Vec<&str> = graph
+        .nodes
+        .iter()
+        .filter(|n| n.kind() == "exe")
+        .map(|n| n.name.as_str())
+        .collect();
+    // `ghost run -p <name>` works like `ghost run <name>`
+    if package.is_none() && build.packages.len() == 1 {
+        package = build.packages.pop();
+    } else if !build.packages.is_empty() {
+        bail!("'ghost run' runs a single package; name it once");
+    }
+    let name = match package {
+        Some(p) => match graph.nodes.iter().find(|n| n.name == p) {
+            Some(n) if n.kind() == "exe" => p,
+            Some(n) => bail!(
+                "package '{p}' is of type '{}', not an exe (executables: {})",
+                n.kind(),
+                exes.join(", ")
+            ),
+            None => bail!("unknown package '{p}' (executables: {})", exes.join(", ")),
+        },
+        None => match exes.as_slice() {
+            [one] => one.to_string(),
+            [] => bail!("the workspace has no exe packages"),
+            many => bail!(
+                "several exe packages, pick one with 'ghost run <package>': {}",
+                many.join(", ")
+            ),
+        },
+    };
+
+    build.packages = vec![name.clone()];
+    let summary = build_workspace(ws, &build)?;
+    let exe = summary
+        .binaries
+        .get(&name)
+        .ok_or_else(|| anyhow::anyhow!("package '{name}' produced no executable"))?;
+    let exe = exe.canonicalize().unwrap_or_else(|_| exe.clone());
+
+    // the rpath already covers this; the variable also helps tools that drop it
+    let lib_dir = Path::new(&summary.build_dir).join("lib");
+    let lib_dir = lib_dir.canonicalize().unwrap_or(lib_dir);
+    let var = if cfg!(target_os = "macos") {
+        "DYLD_LIBRARY_PATH"
+    } else if cfg!(windows) {
+        "PATH"
+    } else {
+        "LD_LIBRARY_PATH"
+    };
+    let mut paths = vec![lib_dir];
+    if let Some(old) = env::var_os(var) {
+        paths.extend(env::split_paths(&old));
+    }
+
+    let mut cmd = std::process::Command::new(&exe);
+    cmd.args(run_args).env(var, env::join_paths(paths)?);
+    if let Some(d) = &cwd {
+        cmd.current_dir(d);
+    }
+    ui::status!("running {}", exe.display());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let err = cmd.exec();
+        bail!("exec {}: {err}", exe.display());
+    }
+    #[cfg(not(unix))]
+    {
+        let status = cmd
+            .status()
+            .with_context(|| format!("run {}", exe.display()))?;
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+fn lib_artifact(node: &graph::PkgNode, build_dir: &str, os: &str) -> String {
+    let name = &node.name;
+    match node.kind() {
+        "shared" => format!(
+            "{}/lib/{}",
+            build_dir,
+            shared_lib_names(name, node.manifest.package.version.as_deref(), os).link
+        ),
+        _ => format!("{}/lib/lib{}.a", build_dir, name),
+    }
+}
+
+// Own objects followed by the libraries the package depends on, dependents first.
+// Also reports whether any of them is a shared library (so an rpath is needed).
+fn link_inputs(
+    graph: &graph::Graph,
+    idx: usize,
+    objs: &[String],
+    build_dir: &str,
+    os: &str,
+) -> (Vec<String>, bool) {
+    let mut inputs = objs.to_vec();
+    let mut links_shared = false;
+    for d in graph.link_closure(idx) {
+        let dep = &graph.nodes[d];
+        links_shared |= dep.kind() == "shared";
+        inputs.push(lib_artifact(dep, build_dir, os));
+    }
+    (inputs, links_shared)
+}
+
+// Per-target `-l`/`-L` from the package's own [public]/[private] link settings and
+// from its dependencies. Static and interface deps contribute both sections (their
+// private libs are still needed at the final link); a shared dep already carries its
+// private libs and those of the statics behind it, so only its public ones are
+// passed on.
+fn push_link_flags(nin: &mut ninja::NinjaBuf, graph: &graph::Graph, idx: usize) {
+    let mut libs: Vec<String> = vec![];
+    let mut dirs: Vec<String> = vec![];
+    let mut collect = |node: &graph::PkgNode, with_private: bool| {
+        let m = &node.manifest;
+        let sections = [Some(&m.public), with_private.then_some(&m.private)];
+        for pv in sections.into_iter().flatten().flatten() {
+            for l in pv.link_libs.iter().flatten() {
+                let flag = lib_flag(l);
+                if !libs.contains(&flag) {
+                    libs.push(flag);
+                }
+            }
+            for d in pv.link_dirs.iter().flatten() {
+                let flag = format!("-L\"{}\"", node.root.join(d).display());
+                if !dirs.contains(&flag) {
+                    dirs.push(flag);
+                }
+            }
+        }
+    };
+    collect(&graph.nodes[idx], true);
+    for (d, with_private) in graph.link_flag_closure(idx) {
+        collect(&graph.nodes[d], with_private);
+    }
+    if !dirs.is_empty() {
+        nin.push(&format!("  libdirs = $libdirs {}", dirs.join(" ")));
+    }
+    if !libs.is_empty() {
+        nin.push(&format!("  libs = $libs {}", libs.join(" ")));
+    }
+}
+
+struct SharedNames {
+    real: String,
+    soname: String,
+    link: String,
+}
+
+// ELF: libfoo.so.1.2.3 (file), libfoo.so.1 (soname), libfoo.so (link name).
+// macOS: libfoo.1.2.3.dylib, libfoo.1.dylib, libfoo.dylib.
+fn shared_lib_names(name: &str, version: Option<&str>, os: &str) -> SharedNames {
+    let versioned = |v: &str| {
+        if os == "macos" {
+            format!("lib{name}.{v}.dylib")
+        } else {
+            format!("lib{name}.so.{v}")
+        }
+    };
+    let link = if os == "macos" {
+        format!("lib{name}.dylib")
+    } else {
+        format!("lib{name}.so")
+    };
+    match version.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => SharedNames {
+            real: versioned(v),
+            soname: versioned(v.split('.').next().unwrap_or(v)),
+            link,
+        },
+        None => SharedNames {
+            real: link.clone(),
+            soname: link.clone(),
+            link,
+        },
+    }
+}
+
+// `rel` is the path from the linked binary's directory to <builddir>/lib.
+fn rpath_flag(os: &str, rel: &str) -> String {
+    if os == "macos" {
+        format!("-Wl,-rpath,@loader_path/{rel}")
+    } else {
+        format!("-Wl,-rpath,'$$ORIGIN/{rel}'")
+    }
+}
+
+fn include_dirs_vars(graph: &graph::Graph, idx: usize) -> String {
+    let node = &graph.nodes[idx];
+    let pkg = &node.manifest;
+    let pkg_root = &node.root;
     let mut incs: Vec<String> = Vec::new();
 
     for d in ["include", "src"] {
@@ -441,7 +1218,7 @@ fn include_dirs_vars(
             incs.push(format!("-I\"{}\"", p.display()));
         }
     }
-    let gen = pkg_root.join(".gen");
+    let gen = pkg_root.join(GEN_DIR);
     if gen.exists() {
         incs.push(format!("-I\"{}\"", gen.display()));
     }
@@ -461,24 +1238,24 @@ fn include_dirs_vars(
         push_dirs(pv.include_dirs.clone(), &mut incs);
     }
 
-    if let Some(deps) = &pkg.deps {
-        if let Some(list) = &deps.direct {
-            for dep_name in list {
-                if let Some(meta) = dep_map.get(dep_name) {
-                    for d in &meta.public_includes {
-                        let p = meta.root.join(d);
-                        incs.push(format!("-I\"{}\"", p.display()));
-                    }
-                    let def_inc = meta.root.join("include");
-                    if def_inc.exists() {
-                        incs.push(format!("-I\"{}\"", def_inc.display()));
-                    }
-                    let dep_gen = meta.root.join(".gen");
-                    if dep_gen.exists() {
-                        incs.push(format!("-I\"{}\"", dep_gen.display()));
-                    }
-                }
-            }
+    for d in graph.usage_closure(idx) {
+        let dep = &graph.nodes[d];
+        let public = dep.manifest.public.as_ref();
+        for d in public
+            .and_then(|p| p.include_dirs.as_ref())
+            .into_iter()
+            .flatten()
+        {
+            let p = dep.root.join(d);
+            incs.push(format!("-I\"{}\"", p.display()));
+        }
+        let def_inc = dep.root.join("include");
+        if def_inc.exists() {
+            incs.push(format!("-I\"{}\"", def_inc.display()));
+        }
+        let dep_gen = dep.root.join(GEN_DIR);
+        if dep_gen.exists() {
+            incs.push(format!("-I\"{}\"", dep_gen.display()));
         }
     }
 
@@ -486,3 +1263,40 @@ fn include_dirs_vars(
     incs.dedup();
     incs.join(" ")
 }
+
+// Headers and other non-compiled `[[generate]]` outputs that the compiles of `idx`
+// may include: its own and those of the packages whose include dirs it gets.
+fn generated_headers(graph: &graph::Graph, idx: usize) -> Vec<String> {
+    std::iter::once(idx)
+        .chain(graph.usage_closure(idx))
+        .flat_map(|i| {
+            let node = &graph.nodes[i];
+            node.manifest
+                .generated()
+                .into_iter()
+                .filter(|f| !is_compile_src(f))
+                .map(|f| format!("{}/{}", node.root.display(), f))
+        })
+        .collect()
+}
+
+// Own public + private defines first, then public defines of dependencies.
+fn defines_vars(graph: &graph::Graph, idx: usize) -> String {
+    let pkg = &graph.nodes[idx].manifest;
+    let mut defs: Vec<String> = Vec::new();
+    let mut push = |d: &String| {
+        let flag = format!("-D{}", d);
+        if !defs.contains(&flag) {
+            defs.push(flag);
+        }
+    };
+    for pv in [&pkg.public, &pkg.private].into_iter().flatten() {
+        pv.defines.iter().flatten().for_each(&mut push);
+    }
+    for d in graph.usage_closure(idx) {
+        if let Some(pv) = &graph.nodes[d].manifest.public {
+            pv.defines.iter().flatten().for_each(&mut push);
+        }
+    }
+    defs.join(" ")
+}
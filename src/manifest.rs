@@ -1,24 +1,40 @@
-use anyhow::{bail, Context, Result};
-use serde::Deserialize;
-use std::{collections::HashMap, fs};
+use crate::error::{GhostError, Location};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path};
 
 #[derive(Debug, serde::Deserialize)]
 pub struct BuildDir {
-    pub dir: String,
+    /// Relative to the workspace root (default: `build`).
+    pub dir: Option<String>,
+    /// Absolute directory outside the source tree; takes precedence over `dir`.
+    #[serde(rename = "out-of-tree", alias = "out_of_tree")]
+    pub out_of_tree: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ProjectRoot {
-    pub project: Option<ProjectMeta>,
     pub workspace: Option<Workspace>,
     pub profile: Option<HashMap<String, ProfileFrag>>,
-    pub build_dir: Option<BuildDir>,
+    #[serde(alias = "build_dir")]
+    pub builddir: Option<BuildDir>,
+    pub cache: Option<CacheConfig>,
+    pub hooks: Option<HooksConfig>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct HooksConfig {
+    /// Capabilities granted to `build.lua`: `exec`, `fs.read`, `fs.write`.
+    #[serde(default)]
+    pub allow: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct ProjectMeta {
-    pub name: String,
-    pub version: String,
+pub struct CacheConfig {
+    /// `http://host:port[/prefix]` of a bazel-remote style cache.
+    pub remote: Option<String>,
+    /// `read-only` (default) or `read-write`.
+    pub mode: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,30 +46,95 @@ pub struct Workspace {
 pub struct ProfileFrag {
     pub defines: Option<Vec<String>>,
     pub exclude: Option<Vec<String>>,
+    pub opt_level: Option<String>,
+    pub debug_info: Option<bool>,
+    pub cflags: Option<Vec<String>>,
+    pub cxxflags: Option<Vec<String>>,
+    pub ldflags: Option<Vec<String>>,
+    pub sanitizers: Option<Vec<String>>,
+    pub lto: Option<Lto>,
 }
 
-#[derive(Debug, Deserialize)]
+/// `lto = true | false | "full" | "thin"`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Lto {
+    Enabled(bool),
+    Mode(String),
+}
+
+impl ProfileFrag {
+    /// Layers `over` on top of `self`: scalars are replaced, lists are appended.
+    pub fn merge(&mut self, over: &ProfileFrag) {
+        fn append(dst: &mut Option<Vec<String>>, src: &Option<Vec<String>>) {
+            if let Some(src) = src {
+                let dst = dst.get_or_insert_with(Vec::new);
+                for v in src {
+                    if !dst.contains(v) {
+                        dst.push(v.clone());
+                    }
+                }
+            }
+        }
+        append(&mut self.defines, &over.defines);
+        append(&mut self.exclude, &over.exclude);
+        append(&mut self.cflags, &over.cflags);
+        append(&mut self.cxxflags, &over.cxxflags);
+        append(&mut self.ldflags, &over.ldflags);
+        append(&mut self.sanitizers, &over.sanitizers);
+        if over.opt_level.is_some() {
+            self.opt_level = over.opt_level.clone();
+        }
+        if over.debug_info.is_some() {
+            self.debug_info = over.debug_info;
+        }
+        if over.lto.is_some() {
+            self.lto = over.lto.clone();
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PackageManifest {
     pub package: Package,
+    #[serde(default)]
     pub sources: Sources,
     pub public: Option<PubPriv>,
     pub private: Option<PubPriv>,
     pub deps: Option<Deps>,
+    #[serde(default)]
+    pub generate: Vec<Generate>,
+}
+
+impl PackageManifest {
+    /// Outputs of the `[[generate]]` steps, relative to the package root.
+    pub fn generated(&self) -> Vec<String> {
+        self.generate
+            .iter()
+            .flat_map(|g| &g.outputs)
+            .map(|o| format!("{GEN_DIR}/{o}"))
+            .collect()
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Package {
     pub name: String,
     pub version: Option<String>,
     pub r#type: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Sources {
+    #[serde(default)]
     pub files: Vec<String>,
+    /// Globs `ghost check` uses to find files on disk that are missing from `files`
+    /// (default: C and C++ sources).
+    #[serde(default)]
+    pub include: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct PubPriv {
     pub include_dirs: Option<Vec<String>>,
     pub defines: Option<Vec<String>>,
@@ -61,32 +142,136 @@ pub struct PubPriv {
     pub link_dirs: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Deps {
     pub direct: Option<Vec<String>>,
     pub private: Option<Vec<String>>,
 }
 
+/// A `[[generate]]` step: a command that turns `inputs` into `outputs`, run as part
+/// of the build whenever an input changed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Generate {
+    /// Run from the package directory. `$in` and `$out` expand to the input and
+    /// output paths; `$$` is a literal `$`.
+    pub command: String,
+    /// Relative to the package root; may name outputs of other steps (`.gen/...`).
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// Relative to the package's `.gen/` directory.
+    pub outputs: Vec<String>,
+}
+
+/// Where `[[generate]]` outputs go, inside the package. It is on the include path
+/// of the package and its dependents.
+pub const GEN_DIR: &str = ".gen";
+
 pub fn load_root_manifest(path: &str) -> Result<ProjectRoot> {
     let txt = fs::read_to_string(path).with_context(|| format!("read {path}"))?;
-    let root: ProjectRoot = toml::from_str(&txt).with_context(|| "parse ghost.build")?;
-    Ok(root)
+    Ok(parse(path, &txt)?)
 }
 
 pub fn load_package_manifest(path: &str) -> Result<PackageManifest> {
     let txt = fs::read_to_string(path).with_context(|| format!("read {path}"))?;
-    let pkg: PackageManifest = toml::from_str(&txt).with_context(|| "parse package ghost.build")?;
+    let pkg: PackageManifest = parse(path, &txt)?;
+    if !PACKAGE_TYPES.contains(&pkg.package.r#type.as_str()) {
+        // parse again to point at the value
+        #[derive(Deserialize)]
+        struct Probe {
+            package: ProbePackage,
+        }
+        #[derive(Deserialize)]
+        struct ProbePackage {
+            r#type: toml::Spanned<String>,
+        }
+        let span = toml::from_str::<Probe>(&txt)
+            .ok()
+            .map(|p| p.package.r#type.span());
+        return Err(GhostError::BadPackageType {
+            kind: pkg.package.r#type,
+            at: Location::new(path, &txt, span),
+        }
+        .into());
+    }
+    assert_package(&pkg, path)?;
     Ok(pkg)
 }
 
-pub fn assert_package(pkg: &PackageManifest) -> Result<()> {
+/// Deserializes a TOML file, pointing at the offending line on error.
+pub fn parse<T: serde::de::DeserializeOwned>(path: &str, txt: &str) -> Result<T, GhostError> {
+    toml::from_str(txt).map_err(|e| GhostError::Manifest {
+        message: e.message().to_string(),
+        at: Location::new(path, txt, e.span()),
+    })
+}
+
+const PACKAGE_TYPES: [&str; 5] = ["static", "shared", "interface", "exe", "test"];
+
+pub fn is_compile_src(p: &str) -> bool {
+    matches!(
+        std::path::Path::new(p).extension().and_then(|s| s.to_str()),
+        Some("c" | "cc" | "cpp" | "cxx")
+    )
+}
+
+/// Checks that do not depend on the rest of the workspace. `path` is only used
+/// in error messages.
+pub fn assert_package(pkg: &PackageManifest, path: &str) -> Result<(), GhostError> {
     let t = pkg.package.r#type.as_str();
-    match t {
-        "static" | "shared" | "interface" | "exe" | "test" => {}
-        _ => bail!("unsupported package.type: {t}"),
+    let invalid = |message: String| GhostError::Manifest {
+        message,
+        at: Location::new(path, "", None),
+    };
+    if !PACKAGE_TYPES.contains(&t) {
+        return Err(GhostError::BadPackageType {
+            kind: t.to_string(),
+            at: Location::new(path, "", None),
+        });
+    }
+    let generated = pkg.generated();
+    for g in &pkg.generate {
+        if g.command.trim().is_empty() || g.command.contains('\n') {
+            return Err(invalid(
+                "[[generate]] command must be a single, non-empty line".into(),
+            ));
+        }
+        if g.outputs.is_empty() {
+            return Err(invalid(format!(
+                "[[generate]] \"{}\" must list its outputs",
+                g.command
+            )));
+        }
+        for o in &g.outputs {
+            if Path::new(o).is_absolute() || o.split(['/', '\\']).any(|c| c == "..") {
+                return Err(invalid(format!(
+                    "[[generate]] output '{o}' must be a relative path inside {GEN_DIR}/"
+                )));
+            }
+        }
+    }
+    for (i, f) in generated.iter().enumerate() {
+        if generated[..i].contains(f) {
+            return Err(invalid(format!(
+                "[[generate]] output '{f}' is produced by more than one step"
+            )));
+        }
     }
-    if pkg.sources.files.is_empty() {
-        bail!("sources.files must not be empty (explicit sources only)");
+    if t == "interface" {
+        if let Some(f) = pkg
+            .sources
+            .files
+            .iter()
+            .chain(&generated)
+            .find(|f| is_compile_src(f))
+        {
+            return Err(invalid(format!(
+                "interface package must not list compilable sources (found {f})"
+            )));
+        }
+    } else if pkg.sources.files.is_empty() && !generated.iter().any(|f| is_compile_src(f)) {
+        return Err(invalid(
+            "sources.files must not be empty (explicit sources only)".into(),
+        ));
     }
     Ok(())
 }
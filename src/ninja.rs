@@ -1,30 +1,260 @@
 use anyhow::Result;
+use std::collections::HashMap;
 use std::fs;
 
-pub struct NinjaBuf(String);
+/// A `build` statement, as recorded from the emitted text.
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub rule: String,
+    pub outputs: Vec<String>,
+    pub inputs: Vec<String>,
+    pub implicit: Vec<String>,
+    pub order_only: Vec<String>,
+    vars: HashMap<String, String>,
+}
+
+enum Scope {
+    Top,
+    Rule(String),
+    Edge(usize),
+}
+
+/// The generated `build.ninja`. Besides the text, every line is also parsed into
+/// rules, top-level variables and edges, so the native executor runs exactly the
+/// graph Ninja would.
+pub struct NinjaBuf {
+    text: String,
+    vars: HashMap<String, String>,
+    rules: HashMap<String, HashMap<String, String>>,
+    edges: Vec<Edge>,
+    scope: Scope,
+}
+
 impl NinjaBuf {
     pub fn new() -> Self {
-        Self(String::new())
+        Self {
+            text: String::new(),
+            vars: HashMap::new(),
+            rules: HashMap::new(),
+            edges: vec![],
+            scope: Scope::Top,
+        }
     }
     pub fn push(&mut self, s: &str) {
-        self.0.push_str(s);
-        self.0.push('\n');
+        self.text.push_str(s);
+        self.text.push('\n');
+        for line in s.lines() {
+            self.record(line);
+        }
     }
     pub fn write_to(&self, path: &str) -> Result<()> {
-        fs::write(path, &self.0)?;
+        fs::write(path, &self.text)?;
         Ok(())
     }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    fn record(&mut self, line: &str) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return;
+        }
+        if line.starts_with(' ') {
+            let Some((k, v)) = split_binding(trimmed) else {
+                return;
+            };
+            match &self.scope {
+                // rule bindings are evaluated per edge, when the command is built
+                Scope::Rule(r) => {
+                    let r = r.clone();
+                    self.rules.entry(r).or_default().insert(k, v.to_string());
+                }
+                // edge bindings see the edge's earlier bindings, then the file scope
+                Scope::Edge(i) => {
+                    let i = *i;
+                    let edge = &self.edges[i];
+                    let val = expand(v, &|name| {
+                        edge.vars.get(name).or_else(|| self.vars.get(name)).cloned()
+                    });
+                    self.edges[i].vars.insert(k, val);
+                }
+                Scope::Top => {}
+            }
+            return;
+        }
+        if let Some(name) = trimmed.strip_prefix("rule ") {
+            self.rules.entry(name.trim().to_string()).or_default();
+            self.scope = Scope::Rule(name.trim().to_string());
+        } else if let Some(rest) = trimmed.strip_prefix("build ") {
+            let edge = self.parse_edge(rest);
+            self.edges.push(edge);
+            self.scope = Scope::Edge(self.edges.len() - 1);
+        } else if let Some((k, v)) = split_binding(trimmed) {
+            let val = expand(v, &|name| self.vars.get(name).cloned());
+            self.vars.insert(k, val);
+            self.scope = Scope::Top;
+        }
+    }
+
+    fn parse_edge(&self, rest: &str) -> Edge {
+        let lookup = |name: &str| self.vars.get(name).cloned();
+        let words = split_words(rest);
+        let colon = words
+            .iter()
+            .position(|w| w.ends_with(':') && !w.ends_with("$:"));
+        let (outs, ins) = match colon {
+            Some(c) => (&words[..=c], &words[c + 1..]),
+            None => (&words[..], &[][..]),
+        };
+        let outputs = outs
+            .iter()
+            .map(|w| w.strip_suffix(':').unwrap_or(w))
+            .filter(|w| !w.is_empty())
+            .map(|w| expand(w, &lookup))
+            .collect();
+        let mut edge = Edge {
+            rule: ins.first().cloned().unwrap_or_default(),
+            outputs,
+            inputs: vec![],
+            implicit: vec![],
+            order_only: vec![],
+            vars: HashMap::new(),
+        };
+        let mut section = 0;
+        for w in ins.iter().skip(1) {
+            match w.as_str() {
+                "|" => section = 1,
+                "||" => section = 2,
+                _ => {
+                    let p = expand(w, &lookup);
+                    match section {
+                        0 => edge.inputs.push(p),
+                        1 => edge.implicit.push(p),
+                        _ => edge.order_only.push(p),
+                    }
+                }
+            }
+        }
+        edge
+    }
+
+    /// Evaluates a rule binding (`command`, `depfile`, ...) for `edge`, with Ninja's
+    /// scoping: `$in`/`$out`, then the edge's bindings, then the rule's, then the file's.
+    pub fn binding(&self, edge: &Edge, key: &str) -> Option<String> {
+        let rule = self.rules.get(&edge.rule)?;
+        let raw = rule.get(key)?;
+        Some(self.eval(edge, rule, raw, 0))
+    }
+
+    fn eval(&self, edge: &Edge, rule: &HashMap<String, String>, raw: &str, depth: usize) -> String {
+        expand(raw, &|name| match name {
+            "in" => Some(edge.inputs.join(" ")),
+            "out" => Some(edge.outputs.join(" ")),
+            _ => edge
+                .vars
+                .get(name)
+                .cloned()
+                .or_else(|| {
+                    rule.get(name)
+                        .filter(|_| depth < 8)
+                        .map(|r| self.eval(edge, rule, r, depth + 1))
+                })
+                .or_else(|| self.vars.get(name).cloned()),
+        })
+    }
+}
+
+fn split_binding(line: &str) -> Option<(String, &str)> {
+    let (k, v) = line.split_once('=')?;
+    let k = k.trim();
+    if k.is_empty() || k.contains(' ') {
+        return None;
+    }
+    Some((k.to_string(), v.trim()))
+}
+
+// Splits on unescaped spaces; `$ ` and `$:` stay part of the word.
+fn split_words(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut cur = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => {
+                cur.push(c);
+                if let Some(n) = chars.next() {
+                    cur.push(n);
+                }
+            }
+            ' ' => {
+                if !cur.is_empty() {
+                    words.push(std::mem::take(&mut cur));
+                }
+            }
+            _ => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        words.push(cur);
+    }
+    words
+}
+
+/// Expands `$name`, `${name}`, `$$`, `$ ` and `$:`. Unknown variables expand to "".
+pub fn expand(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek().copied() {
+            Some(c @ ('$' | ' ' | ':')) => {
+                out.push(c);
+                chars.next();
+            }
+            Some('{') => {
+                chars.next();
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                out.push_str(&lookup(&name).unwrap_or_default());
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                out.push_str(&lookup(&name).unwrap_or_default());
+            }
+            _ => out.push('$'),
+        }
+    }
+    out
+}
+
+/// Escapes `$` so that Ninja passes `s` through unchanged.
+pub fn escape(s: &str) -> String {
+    s.replace('$', "$$")
 }
 
 pub fn emit_prelude(n: &mut NinjaBuf) {
     n.push("rule cc");
-    n.push("  command = $cc -MMD -MF $out.d $cflags $includes -c $in -o $out");
+    n.push("  command = $launcher $cc -MMD -MF $out.d $cflags $defines $includes -c $in -o $out");
     n.push("  depfile = $out.d");
     n.push("  deps = gcc");
     n.push("");
 
     n.push("rule cxx");
-    n.push("  command = $cxx -MMD -MF $out.d $cxxflags $includes -c $in -o $out");
+    n.push(
+        "  command = $launcher $cxx -MMD -MF $out.d $cxxflags $defines $includes -c $in -o $out",
+    );
     n.push("  depfile = $out.d");
     n.push("  deps = gcc");
     n.push("");
@@ -38,10 +268,97 @@ pub fn emit_prelude(n: &mut NinjaBuf) {
     n.push("");
 
     n.push("rule link_exe");
-    n.push("  command = $link $linkflags $in -o $out $ldflags $libdirs $libs");
+    n.push("  command = $launcher $link $linkflags $in -o $out $ldflags $libdirs $libs");
+    n.push("");
+
+    n.push("rule link_shared");
+    n.push("  command = $launcher $link $linkflags -shared -Wl,-soname,$soname $in -o $out $ldflags $libdirs $libs");
+    n.push("");
+
+    n.push("rule link_shared_macos");
+    n.push("  command = $launcher $link $linkflags -dynamiclib -Wl,-install_name,@rpath/$soname $in -o $out $ldflags $libdirs $libs");
+    n.push("");
+
+    n.push("rule symlink");
+    n.push("  command = ln -sf $target $out");
     n.push("");
 
     n.push("rule link_exe_msvc");
     n.push("  command = $link /OUT:$out $in $ldflags $libdirs $libs");
     n.push("");
+
+    // [[generate]] steps; `cmd` has $in/$out already expanded
+    n.push("rule gen");
+    n.push("  command = cd \"$dir\" && $cmd");
+    n.push("");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(name: &str) -> Option<String> {
+        match name {
+            "a" => Some("A".into()),
+            "long_name-1" => Some("L".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expand_escapes_and_variables() {
+        assert_eq!(expand("$$a", &vars), "$a");
+        assert_eq!(expand("x$ y$:z", &vars), "x y:z");
+        assert_eq!(expand("${a}b $a.o", &vars), "Ab A.o");
+        assert_eq!(expand("$long_name-1/", &vars), "L/");
+        assert_eq!(expand("[$unknown]", &vars), "[]");
+    }
+
+    #[test]
+    fn split_words_keeps_escaped_spaces() {
+        assert_eq!(
+            split_words("a$ b  c$:d e"),
+            ["a$ b", "c$:d", "e"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn edge_inputs_by_section() {
+        let mut n = NinjaBuf::new();
+        n.push("dir = out");
+        n.push("build $dir/my$ file.o c$:/x.o: cc in$ a.c $dir/b.c | imp.h || gen.h");
+        let e = &n.edges()[0];
+        assert_eq!(e.rule, "cc");
+        assert_eq!(e.outputs, ["out/my file.o", "c:/x.o"]);
+        assert_eq!(e.inputs, ["in a.c", "out/b.c"]);
+        assert_eq!(e.implicit, ["imp.h"]);
+        assert_eq!(e.order_only, ["gen.h"]);
+    }
+
+    #[test]
+    fn bindings_are_scoped_edge_rule_file() {
+        let mut n = NinjaBuf::new();
+        n.push("flags = -O2");
+        n.push("name = file");
+        n.push("rule r");
+        n.push("  msg = rule-$name");
+        n.push("  command = tool $flags $msg $in -o $out");
+        n.push("build a: r x");
+        n.push("build b: r y");
+        n.push("  flags = $flags -g");
+        n.push("  name = edge");
+        n.push("  extra = $flags");
+        let (a, b) = (&n.edges()[0], &n.edges()[1]);
+        assert_eq!(
+            n.binding(a, "command").unwrap(),
+            "tool -O2 rule-file x -o a"
+        );
+        // edge bindings shadow the file's and see the edge's earlier ones
+        assert_eq!(
+            n.binding(b, "command").unwrap(),
+            "tool -O2 -g rule-edge y -o b"
+        );
+        assert_eq!(b.vars["extra"], "-O2 -g");
+        assert_eq!(n.binding(a, "depfile"), None);
+    }
 }
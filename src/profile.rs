@@ -1,47 +1,227 @@
 use crate::context::{Profile, Toolchain};
-use anyhow::{Context, Result};
+use crate::manifest::{Lto, ProfileFrag};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 
 #[derive(Debug, Deserialize)]
 pub struct ProfileFile {
     pub toolchain: Toolchain,
-    pub env: Option<std::collections::HashMap<String, String>>, // future use
 }
 
 pub fn load_profile(path: &str) -> Result<ProfileFile> {
     let txt = fs::read_to_string(path).with_context(|| format!("read {path}"))?;
-    let pf: ProfileFile = toml::from_str(&txt).with_context(|| "parse ghost.profile")?;
-    Ok(pf)
+    Ok(crate::manifest::parse(path, &txt)?)
 }
 
-pub fn default_profile() -> (Toolchain, Profile) {
-    (
-        Toolchain {
-            cc: "clang".into(),
-            cxx: "clang++".into(),
-            ar: "ar".into(),
-            rc: None,
-            sysroot: None,
-            target_triple: None,
-            cflags: vec!["-Wall".into(), "-Wextra".into()],
-            cxxflags: vec!["-std=c++20".into(), "-O2".into()],
-            ldflags: vec![],
-
-            arflags: Some(vec!["rcs".into()]),
-            libdirs: Some(vec!["build/lib".into()]),
-            libs: Some(vec![]),
-
-            link_mode: Some("driver".into()),
-            link: None,
-            link_c: Some("clang".into()),
-            link_cxx: Some("clang++".into()),
-            fuse_ld: None,
-        },
-        Profile {
-            name: "debug".into(),
-            defines: vec!["DEBUG=1".into()],
-            exclude: vec![],
+pub fn default_toolchain() -> Toolchain {
+    Toolchain {
+        cc: "clang".into(),
+        cxx: "clang++".into(),
+        ar: "ar".into(),
+        rc: None,
+        sysroot: None,
+        target_triple: None,
+        cflags: vec!["-Wall".into(), "-Wextra".into()],
+        cxxflags: vec!["-std=c++20".into()],
+        ldflags: vec![],
+
+        arflags: Some(vec!["rcs".into()]),
+        libdirs: Some(vec![]),
+        libs: Some(vec![]),
+
+        link_mode: Some("driver".into()),
+        link: None,
+        link_c: Some("clang".into()),
+        link_cxx: Some("clang++".into()),
+        fuse_ld: None,
+    }
+}
+
+fn builtin_profile(name: &str) -> Option<ProfileFrag> {
+    let (opt, debug_info, defines): (&str, bool, &[&str]) = match name {
+        "debug" => ("0", true, &["DEBUG=1"]),
+        "release" => ("3", false, &["NDEBUG"]),
+        "relwithdebinfo" => ("2", true, &["NDEBUG"]),
+        _ => return None,
+    };
+    Some(ProfileFrag {
+        opt_level: Some(opt.into()),
+        debug_info: Some(debug_info),
+        defines: Some(defines.iter().map(|d| d.to_string()).collect()),
+        ..Default::default()
+    })
+}
+
+/// Layers the built-in defaults for `name` (if any), `[profile.<name>]` from the
+/// root manifest and the command line overrides, in that order.
+pub fn resolve_profile(
+    frags: Option<&HashMap<String, ProfileFrag>>,
+    name: &str,
+    overrides: &ProfileFrag,
+) -> Result<Profile> {
+    let builtin = builtin_profile(name);
+    let project = frags.and_then(|f| f.get(name));
+    if builtin.is_none() && project.is_none() {
+        let mut known: Vec<&str> = frags
+            .map(|f| f.keys().map(|k| k.as_str()).collect())
+            .unwrap_or_default();
+        for b in ["debug", "release", "relwithdebinfo"] {
+            if !known.contains(&b) {
+                known.push(b);
+            }
+        }
+        known.sort();
+        bail!(
+            "unknown config '{name}' (no [profile.{name}] in ghost.build; available: {})",
+            known.join(", ")
+        );
+    }
+
+    let mut frag = builtin.unwrap_or_default();
+    if let Some(p) = project {
+        frag.merge(p);
+    }
+    frag.merge(overrides);
+
+    let lto = match frag.lto {
+        None | Some(Lto::Enabled(false)) => None,
+        Some(Lto::Enabled(true)) => Some("full".to_string()),
+        Some(Lto::Mode(m)) => match m.as_str() {
+            "off" => None,
+            "full" | "thin" => Some(m),
+            other => bail!(
+                "[profile.{name}] lto: expected true, false, \"full\" or \"thin\", got \"{other}\""
+            ),
         },
-    )
+    };
+    Ok(Profile {
+        name: name.to_string(),
+        defines: frag.defines.unwrap_or_default(),
+        exclude: frag.exclude.unwrap_or_default(),
+        opt_level: frag.opt_level,
+        debug_info: frag.debug_info,
+        cflags: frag.cflags.unwrap_or_default(),
+        cxxflags: frag.cxxflags.unwrap_or_default(),
+        ldflags: frag.ldflags.unwrap_or_default(),
+        sanitizers: frag.sanitizers.unwrap_or_default(),
+        lto,
+    })
+}
+
+/// Folds the resolved profile into the toolchain flags. A profile `opt_level`
+/// replaces any `-O` coming from the toolchain file, and `debug_info` any `-g`.
+pub fn apply_profile(tc: &mut Toolchain, p: &Profile) {
+    let mut common: Vec<String> = vec![];
+    if let Some(o) = &p.opt_level {
+        common.push(format!("-O{}", o.trim_start_matches("-O")));
+    }
+    if p.debug_info == Some(true) {
+        common.push("-g".into());
+    }
+    let mut link: Vec<String> = vec![];
+    if !p.sanitizers.is_empty() {
+        let san = format!("-fsanitize={}", p.sanitizers.join(","));
+        common.push(san.clone());
+        common.push("-fno-omit-frame-pointer".into());
+        link.push(san);
+    }
+    match p.lto.as_deref() {
+        Some("thin") => {
+            common.push("-flto=thin".into());
+            link.push("-flto=thin".into());
+        }
+        Some(_) => {
+            common.push("-flto".into());
+            link.push("-flto".into());
+        }
+        None => {}
+    }
+
+    let layer = |base: &mut Vec<String>, extra: &[String]| {
+        if p.opt_level.is_some() {
+            base.retain(|f| !f.starts_with("-O"));
+        }
+        if p.debug_info.is_some() {
+            base.retain(|f| !f.starts_with("-g"));
+        }
+        base.extend(common.iter().cloned());
+        base.extend(extra.iter().cloned());
+    };
+    layer(&mut tc.cflags, &p.cflags);
+    layer(&mut tc.cxxflags, &p.cxxflags);
+    tc.ldflags.extend(link);
+    tc.ldflags.extend(p.ldflags.iter().cloned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toolchain(flags: &[&str]) -> Toolchain {
+        let mut tc = default_toolchain();
+        tc.cflags = flags.iter().map(|f| f.to_string()).collect();
+        tc
+    }
+
+    fn profile(name: &str, project: &str, overrides: ProfileFrag) -> Profile {
+        let frags: HashMap<String, ProfileFrag> = toml::from_str(project).unwrap();
+        resolve_profile(Some(&frags), name, &overrides).unwrap()
+    }
+
+    #[test]
+    fn project_and_command_line_layer_over_builtins() {
+        let p = profile(
+            "release",
+            "[release]\ndefines = [\"NDEBUG\", \"FAST=1\"]\nlto = \"thin\"\n",
+            ProfileFrag {
+                opt_level: Some("s".into()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(p.opt_level.as_deref(), Some("s"));
+        assert_eq!(p.debug_info, Some(false));
+        assert_eq!(p.defines, ["NDEBUG", "FAST=1"]);
+        assert_eq!(p.lto.as_deref(), Some("thin"));
+
+        let custom = profile(
+            "asan",
+            "[asan]\nsanitizers = [\"address\"]\n",
+            Default::default(),
+        );
+        assert_eq!((custom.opt_level, custom.debug_info), (None, None));
+
+        let err = resolve_profile(None, "fast", &Default::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown config 'fast' (no [profile.fast] in ghost.build; available: debug, release, relwithdebinfo)"
+        );
+    }
+
+    #[test]
+    fn debug_defines_debug() {
+        let p = profile(
+            "debug",
+            "[debug]\ndefines = [\"TRACE=1\"]\n",
+            Default::default(),
+        );
+        assert_eq!(p.defines, ["DEBUG=1", "TRACE=1"]);
+    }
+
+    #[test]
+    fn profile_replaces_toolchain_opt_and_debug_flags() {
+        let mut tc = toolchain(&["-Wall", "-O2", "-g3"]);
+        apply_profile(&mut tc, &profile("release", "", Default::default()));
+        assert_eq!(tc.cflags, ["-Wall", "-O3"]);
+
+        let mut tc = toolchain(&["-O0", "-gline-tables-only"]);
+        apply_profile(&mut tc, &profile("relwithdebinfo", "", Default::default()));
+        assert_eq!(tc.cflags, ["-O2", "-g"]);
+
+        // a profile that says nothing about them keeps the toolchain's
+        let mut tc = toolchain(&["-O2", "-g"]);
+        apply_profile(&mut tc, &profile("asan", "[asan]\n", Default::default()));
+        assert_eq!(tc.cflags, ["-O2", "-g"]);
+    }
 }
@@ -0,0 +1,293 @@
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BODY: u64 = 1 << 30;
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// A remote cache speaking the bazel-remote HTTP protocol: `GET`/`PUT /ac/<key>`
+/// for action results and `/cas/<sha256>` for content-addressed blobs.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    host: String,
+    port: u16,
+    prefix: String,
+}
+
+impl Remote {
+    pub fn parse(url: &str) -> Result<Remote> {
+        let rest = match url.split_once("://") {
+            Some(("http", rest)) => rest,
+            Some((scheme, _)) => bail!(
+                "remote cache '{url}': only http:// is supported, not {scheme}:// \
+                 (put a TLS-terminating proxy in front of the cache)"
+            ),
+            None => url,
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) => (
+                h,
+                p.parse()
+                    .with_context(|| format!("remote cache '{url}': invalid port '{p}'"))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            bail!("remote cache '{url}': missing host");
+        }
+        Ok(Remote {
+            host: host.to_string(),
+            port,
+            prefix: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .with_context(|| format!("resolve {}", self.host))?
+            .next()
+            .ok_or_else(|| anyhow!("resolve {}: no address", self.host))?;
+        let s = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .with_context(|| format!("connect to {}:{}", self.host, self.port))?;
+        s.set_read_timeout(Some(IO_TIMEOUT))?;
+        s.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(s)
+    }
+
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+        let mut s = self.connect()?;
+        write!(
+            s,
+            "{method} {}{path} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.prefix,
+            self.host,
+            self.port,
+            body.len()
+        )?;
+        s.write_all(body)?;
+        s.flush()?;
+        let mut r = BufReader::new(s);
+        let (_, status, len) = read_head(&mut r)?;
+        let mut out = vec![];
+        match len {
+            Some(n) => r.take(n).read_to_end(&mut out)?,
+            None => r.take(MAX_BODY).read_to_end(&mut out)?,
+        };
+        Ok((status.parse().context("invalid HTTP status")?, out))
+    }
+
+    /// `Ok(None)` on a miss; `Err` if the server could not be reached.
+    pub fn get(&self, kind: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.request("GET", &format!("/{kind}/{key}"), &[])? {
+            (200, body) => Ok(Some(body)),
+            (404, _) => Ok(None),
+            (code, _) => bail!("GET /{kind}/{key}: HTTP {code}"),
+        }
+    }
+
+    pub fn put(&self, kind: &str, key: &str, body: &[u8]) -> Result<()> {
+        match self.request("PUT", &format!("/{kind}/{key}"), body)? {
+            (200..=299, _) => Ok(()),
+            (code, _) => bail!("PUT /{kind}/{key}: HTTP {code}"),
+        }
+    }
+}
+
+// The first two words of the start line (version and status of a response, method
+// and path of a request) and the Content-Length.
+fn read_head(r: &mut impl BufRead) -> Result<(String, String, Option<u64>)> {
+    let mut first = String::new();
+    r.read_line(&mut first)?;
+    let mut parts = first.split_whitespace();
+    let a = parts.next().unwrap_or_default().to_string();
+    let b = parts
+        .next()
+        .ok_or_else(|| anyhow!("malformed HTTP message"))?
+        .to_string();
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                len = Some(v.trim().parse().context("invalid Content-Length")?);
+            }
+        }
+    }
+    Ok((a, b, len))
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn respond(s: &mut TcpStream, code: u16, reason: &str, body: &[u8]) -> Result<()> {
+    write!(
+        s,
+        "HTTP/1.1 {code} {reason}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    s.write_all(body)?;
+    Ok(())
+}
+
+fn handle(mut s: TcpStream, dir: &Path, read_only: bool) -> Result<()> {
+    s.set_read_timeout(Some(IO_TIMEOUT))?;
+    let mut r = BufReader::new(s.try_clone()?);
+    let (method, path, len) = read_head(&mut r)?;
+    let mut parts = path.trim_start_matches('/').splitn(2, '/');
+    let (kind, key) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if !matches!(kind, "ac" | "cas") || !valid_key(key) {
+        return respond(
+            &mut s,
+            400,
+            "Bad Request",
+            b"expected /ac/<sha256> or /cas/<sha256>\n",
+        );
+    }
+    let file = dir.join(kind).join(&key[..2]).join(key);
+    match method.as_str() {
+        "GET" | "HEAD" => match fs::read(&file) {
+            Ok(body) if method == "GET" => respond(&mut s, 200, "OK", &body),
+            Ok(_) => respond(&mut s, 200, "OK", b""),
+            Err(_) => respond(&mut s, 404, "Not Found", b""),
+        },
+        "PUT" if read_only => respond(&mut s, 403, "Forbidden", b"read-only cache\n"),
+        "PUT" => {
+            let n = len.unwrap_or(0);
+            if n > MAX_BODY {
+                return respond(&mut s, 413, "Payload Too Large", b"");
+            }
+            let mut body = vec![];
+            r.take(n).read_to_end(&mut body)?;
+            if kind == "cas" && hex::encode(Sha256::digest(&body)) != key {
+                return respond(
+                    &mut s,
+                    400,
+                    "Bad Request",
+                    b"content does not match its hash\n",
+                );
+            }
+            let n = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+            let tmp = dir.join("tmp").join(format!("{key}.{n}"));
+            fs::create_dir_all(tmp.parent().unwrap())?;
+            fs::create_dir_all(file.parent().unwrap())?;
+            fs::write(&tmp, &body)?;
+            fs::rename(&tmp, &file)?;
+            respond(&mut s, 200, "OK", b"")
+        }
+        _ => respond(&mut s, 405, "Method Not Allowed", b""),
+    }
+}
+
+/// `ghost cache-server`: serves `dir` on `addr` until killed.
+pub fn serve(dir: &Path, addr: &str, read_only: bool) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    let listener = TcpListener::bind(addr).with_context(|| format!("bind {addr}"))?;
+    eprintln!(
+        "serving {} on http://{}{}",
+        dir.display(),
+        listener.local_addr()?,
+        if read_only { " (read-only)" } else { "" }
+    );
+    for conn in listener.incoming() {
+        let Ok(conn) = conn else { continue };
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            if let Err(e) = handle(conn, &dir, read_only) {
+                eprintln!("cache-server: {e:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cache server on a free local port, serving `dir` until the test exits.
+    fn server(dir: &Path, read_only: bool) -> Remote {
+        let _ = fs::remove_dir_all(dir);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            for conn in listener.incoming().flatten() {
+                let _ = handle(conn, &dir, read_only);
+            }
+        });
+        Remote::parse(&format!("http://127.0.0.1:{port}")).unwrap()
+    }
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ghost-remote-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn parse_urls() {
+        let r = Remote::parse("cache.internal:9090/ns/").unwrap();
+        assert_eq!(
+            (r.host.as_str(), r.port, r.prefix.as_str()),
+            ("cache.internal", 9090, "/ns")
+        );
+        assert_eq!(Remote::parse("http://cache").unwrap().port, 80);
+        for bad in ["https://cache", "http://:9090", "http://cache:port"] {
+            assert!(Remote::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn put_then_get() {
+        let dir = scratch("rw");
+        let r = server(&dir, false);
+        let key = "ab".repeat(32);
+        assert_eq!(r.get("ac", &key).unwrap(), None);
+        r.put("ac", &key, b"entry\0bytes").unwrap();
+        assert_eq!(
+            r.get("ac", &key).unwrap().as_deref(),
+            Some(&b"entry\0bytes"[..])
+        );
+
+        // blobs are checked against their hash
+        let blob = b"object";
+        assert!(r.put("cas", &key, blob).is_err());
+        let sum = hex::encode(Sha256::digest(blob));
+        r.put("cas", &sum, blob).unwrap();
+        assert!(r.get("ac", "not-a-key").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_rejects_puts() {
+        let dir = scratch("ro");
+        let r = server(&dir, true);
+        let err = r.put("ac", &"cd".repeat(32), b"x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("PUT /ac/{}: HTTP 403", "cd".repeat(32))
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::ui;
+use anyhow::{Context, Result};
+use std::{
+    collections::VecDeque,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for a test's output after it ended, in case something it started
+/// outside its process group still holds its stdout or stderr open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub path: PathBuf,
+    pub cwd: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestOpts {
+    pub filters: Vec<String>,
+    pub jobs: usize,
+    pub timeout: Option<Duration>,
+    pub junit: Option<PathBuf>,
+}
+
+impl Default for TestOpts {
+    fn default() -> Self {
+        Self {
+            filters: vec![],
+            jobs: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            timeout: Some(Duration::from_secs(60)),
+            junit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Passed,
+    Failed(Option<i32>),
+    TimedOut,
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl TestResult {
+    fn passed(&self) -> bool {
+        matches!(self.outcome, Outcome::Passed)
+    }
+}
+
+pub fn run_tests(tests: &[TestCase], opts: &TestOpts) -> Result<Vec<TestResult>> {
+    let selected: VecDeque<(usize, TestCase)> = tests
+        .iter()
+        .filter(|t| opts.filters.is_empty() || opts.filters.iter().any(|f| t.name.contains(f)))
+        .cloned()
+        .enumerate()
+        .collect();
+    let total = selected.len();
+    ui::status!("running {} test(s)", total);
+
+    let queue = Arc::new(Mutex::new(selected));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(total)));
+    let workers = opts.jobs.clamp(1, total.max(1));
+
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let results = Arc::clone(&results);
+            let timeout = opts.timeout;
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let Some((idx, test)) = next else { break };
+                let r = run_one(&test, timeout);
+                let status = match &r.outcome {
+                    Outcome::Passed => "ok".to_string(),
+                    Outcome::Failed(Some(c)) => format!("FAILED (exit code {c})"),
+                    Outcome::Failed(None) => "FAILED (killed by signal)".to_string(),
+                    Outcome::TimedOut => "TIMEOUT".to_string(),
+                    Outcome::Error(e) => format!("ERROR ({e})"),
+                };
+                if !r.passed() || ui::level() > ui::Level::Quiet {
+                    eprintln!(
+                        "test {} ... {} [{:.2}s]",
+                        r.name,
+                        status,
+                        r.duration.as_secs_f64()
+                    );
+                }
+                results.lock().unwrap().push((idx, r));
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join()
+            .map_err(|_| anyhow::anyhow!("test worker panicked"))?;
+    }
+
+    let mut results = Arc::try_unwrap(results)
+        .map_err(|_| anyhow::anyhow!("test results still shared"))?
+        .into_inner()
+        .unwrap();
+    results.sort_by_key(|(idx, _)| *idx);
+    Ok(results.into_iter().map(|(_, r)| r).collect())
+}
+
+fn run_one(test: &TestCase, timeout: Option<Duration>) -> TestResult {
+    let start = Instant::now();
+    let result = |outcome, stdout, stderr| TestResult {
+        name: test.name.clone(),
+        outcome,
+        duration: start.elapsed(),
+        stdout,
+        stderr,
+    };
+
+    let exe = test
+        .path
+        .canonicalize()
+        .unwrap_or_else(|_| test.path.clone());
+    let mut cmd = Command::new(&exe);
+    cmd.current_dir(&test.cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // its own process group, so that what the test forks can be killed with it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+    let mut child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            return result(
+                Outcome::Error(format!("spawn {}: {e}", exe.display())),
+                String::new(),
+                String::new(),
+            )
+        }
+    };
+
+    let out = drain(child.stdout.take());
+    let err = drain(child.stderr.take());
+
+    let outcome = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break Outcome::Passed,
+            Ok(Some(status)) => break Outcome::Failed(status.code()),
+            Ok(None) => {}
+            Err(e) => break Outcome::Error(e.to_string()),
+        }
+        if timeout.is_some_and(|t| start.elapsed() >= t) {
+            kill_group(&mut child);
+            let _ = child.wait();
+            break Outcome::TimedOut;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // anything the test left running goes too
+    kill_group(&mut child);
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let stdout = out.collect(deadline);
+    let stderr = err.collect(deadline);
+    result(outcome, stdout, stderr)
+}
+
+#[cfg(unix)]
+fn kill_group(child: &mut Child) {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Output read from a test's pipe so far.
+struct Drain {
+    buf: Arc<Mutex<Vec<u8>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+impl Drain {
+    /// Waits for the pipe to close, but not past `deadline`.
+    fn collect(self, deadline: Instant) -> String {
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let buf = self.buf.lock().unwrap();
+        String::from_utf8_lossy(&buf).to_string()
+    }
+}
+
+fn drain<R: Read + Send + 'static>(r: Option<R>) -> Drain {
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let out = Arc::clone(&buf);
+    let reader = thread::spawn(move || {
+        let Some(mut r) = r else { return };
+        let mut chunk = [0; 8192];
+        loop {
+            match r.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => out.lock().unwrap().extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+    Drain { buf, reader }
+}
+
+/// Prints failed test output and the pass/fail counts. Returns true if all tests passed.
+pub fn print_summary(results: &[TestResult]) -> bool {
+    let failed: Vec<&TestResult> = results.iter().filter(|r| !r.passed()).collect();
+    for r in &failed {
+        eprintln!("\n---- {} ----", r.name);
+        if !r.stdout.is_empty() {
+            eprintln!("stdout:\n{}", r.stdout.trim_end());
+        }
+        if !r.stderr.is_empty() {
+            eprintln!("stderr:\n{}", r.stderr.trim_end());
+        }
+    }
+    let total_time = results
+        .iter()
+        .fold(0.0, |acc, r| acc + r.duration.as_secs_f64());
+    eprintln!(
+        "\ntest result: {}. {} passed; {} failed; {:.2}s",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        results.len() - failed.len(),
+        failed.len(),
+        total_time
+    );
+    if !failed.is_empty() {
+        eprintln!("failed tests:");
+        for r in &failed {
+            eprintln!("  - {}", r.name);
+        }
+    }
+    failed.is_empty()
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c if (c as u32) < 0x20 && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn write_junit(path: &Path, results: &[TestResult]) -> Result<()> {
+    let failures = results
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Failed(_) | Outcome::TimedOut))
+        .count();
+    let errors = results
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Error(_)))
+        .count();
+    let total_time = results
+        .iter()
+        .fold(0.0, |acc, r| acc + r.duration.as_secs_f64());
+
+    let mut x = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    x.push_str(&format!(
+        "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+        results.len(),
+        failures,
+        errors,
+        total_time
+    ));
+    x.push_str(&format!(
+        "  <testsuite name=\"ghost\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+        results.len(),
+        failures,
+        errors,
+        total_time
+    ));
+    for r in results {
+        x.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"ghost\" time=\"{:.3}\">\n",
+            xml_escape(&r.name),
+            r.duration.as_secs_f64()
+        ));
+        match &r.outcome {
+            Outcome::Passed => {}
+            Outcome::Failed(code) => {
+                let msg = match code {
+                    Some(c) => format!("exit code {c}"),
+                    None => "killed by signal".to_string(),
+                };
+                x.push_str(&format!("      <failure message=\"{}\"/>\n", msg));
+            }
+            Outcome::TimedOut => x.push_str("      <failure message=\"timed out\"/>\n"),
+            Outcome::Error(e) => {
+                x.push_str(&format!("      <error message=\"{}\"/>\n", xml_escape(e)))
+            }
+        }
+        if !r.stdout.is_empty() {
+            x.push_str(&format!(
+                "      <system-out>{}</system-out>\n",
+                xml_escape(&r.stdout)
+            ));
+        }
+        if !r.stderr.is_empty() {
+            x.push_str(&format!(
+                "      <system-err>{}</system-err>\n",
+                xml_escape(&r.stderr)
+            ));
+        }
+        x.push_str("    </testcase>\n");
+    }
+    x.push_str("  </testsuite>\n</testsuites>\n");
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    fs::write(path, x).with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("ghost-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&d);
+        fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    fn result(name: &str, outcome: Outcome) -> TestResult {
+        TestResult {
+            name: name.into(),
+            outcome,
+            duration: Duration::from_millis(250),
+            stdout: String::new(),
+            stderr: String::new(),
+        }
+    }
+
+    #[test]
+    fn xml_escape_drops_control_characters() {
+        assert_eq!(
+            xml_escape("a<b & \"c\" 'd'>\u{1b}[0m\n"),
+            "a&lt;b &amp; &quot;c&quot; &apos;d&apos;&gt;[0m\n"
+        );
+    }
+
+    #[test]
+    fn junit_counts_failures_and_errors() {
+        let mut failed = result("math<int>", Outcome::Failed(Some(3)));
+        failed.stderr = "expected 4 & got 5".into();
+        let results = [
+            result("ok", Outcome::Passed),
+            failed,
+            result("slow", Outcome::TimedOut),
+            result("gone", Outcome::Error("spawn: not found".into())),
+        ];
+        assert!(!print_summary(&results));
+        assert!(print_summary(&results[..1]));
+
+        let path = scratch("junit").join("out/junit.xml");
+        write_junit(&path, &results).unwrap();
+        let x = fs::read_to_string(&path).unwrap();
+        assert!(x.contains(
+            "<testsuite name=\"ghost\" tests=\"4\" failures=\"2\" errors=\"1\" time=\"1.000\">"
+        ));
+        assert!(x.contains("<testcase name=\"math&lt;int&gt;\" classname=\"ghost\""));
+        assert!(x.contains("<failure message=\"exit code 3\"/>"));
+        assert!(x.contains("<system-err>expected 4 &amp; got 5</system-err>"));
+        assert!(x.contains("<failure message=\"timed out\"/>"));
+        assert!(x.contains("<error message=\"spawn: not found\"/>"));
+        let _ = fs::remove_dir_all(path.parent().unwrap().parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_kills_what_the_test_forked() {
+        use std::os::unix::fs::PermissionsExt;
+        let d = scratch("fork");
+        let exe = d.join("forks");
+        // the background sleep keeps stdout open after the shell is killed
+        fs::write(&exe, "#!/bin/sh\necho started\nsleep 30 &\nsleep 30\n").unwrap();
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+        let test = TestCase {
+            name: "forks".into(),
+            path: exe,
+            cwd: d.clone(),
+        };
+        let r = run_one(&test, Some(Duration::from_millis(300)));
+        assert!(matches!(r.outcome, Outcome::TimedOut));
+        assert_eq!(r.stdout, "started\n");
+        assert!(r.duration < DRAIN_TIMEOUT);
+        let _ = fs::remove_dir_all(&d);
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Output verbosity, set once from `-v`/`-q`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+pub fn set_level(l: Level) {
+    LEVEL.store(l as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Quiet,
+        1 => Level::Normal,
+        _ => Level::Verbose,
+    }
+}
+
+/// Progress output (`[3/10] cc ...`, `wrote ...`); suppressed by `--quiet`.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::ui::level() > $crate::ui::Level::Quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use status;
@@ -2,12 +2,15 @@
 extern "C" {
 #include "add.h"
 }
+#include "banner.hpp"
+#include "core/clamp.hpp"
 #include "io.hpp"
 
 int main() {
-  log_line("Ghost in the Shell initializing...");
+  log_line(banner() + " initializing...");
   int r = add(7, 5);
   log_line("Computation result: " + std::to_string(r));
+  log_line("Clamped: " + std::to_string(core::clamp_round(r * 1.7, 0, 20)));
   log_line("System integrity: stable.");
   return 0;
 }
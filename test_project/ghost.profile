@@ -5,6 +5,6 @@ ar = "ar"
 sysroot = ""
 target_triple = ""
 cflags = ["-Wall", "-Wextra"]
-cxxflags = ["-std=c++20", "-O2"]
+cxxflags = ["-std=c++20"]
 arflags = [ "rcs" ]
 ldflags = []
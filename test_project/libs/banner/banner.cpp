@@ -0,0 +1,3 @@
+#include "banner.hpp"
+
+std::string banner() { return "👻 Ghost in the Shell"; }
@@ -0,0 +1,14 @@
+#pragma once
+#include <cmath>
+
+#ifndef GHOST_CORE
+#error "core: GHOST_CORE must be defined by the build system"
+#endif
+
+namespace core {
+
+inline double clamp_round(double v, double lo, double hi) {
+  return std::round(v < lo ? lo : (v > hi ? hi : v));
+}
+
+} // namespace core
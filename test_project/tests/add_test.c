@@ -0,0 +1,19 @@
+#include <stdio.h>
+#include "add.h"
+
+static int failures = 0;
+
+#define CHECK_EQ(a, b)                                                         \
+  do {                                                                         \
+    if ((a) != (b)) {                                                          \
+      fprintf(stderr, "%s:%d: %s != %s\n", __FILE__, __LINE__, #a, #b);        \
+      failures++;                                                              \
+    }                                                                          \
+  } while (0)
+
+int main(void) {
+  CHECK_EQ(add(2, 3), 5);
+  CHECK_EQ(add(-4, 4), 0);
+  CHECK_EQ(add(0, 0), 0);
+  return failures == 0 ? 0 : 1;
+}